@@ -0,0 +1,172 @@
+use clap::{Args, ValueEnum};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+/// Steps bringing the URLs to a canonical form, so the variants of a URL
+/// share an image path. Hosts are always lowercased and IDNA-encoded,
+/// and default ports dropped, for http and https URLs.
+#[derive(Args, Clone, Debug, Default)]
+pub struct Canonicalization {
+    /// Drop the URL fragments
+    #[arg(long)]
+    pub strip_fragment: bool,
+
+    /// Query parameters to drop, a trailing * matching any suffix, e.g. utm_*
+    #[arg(long, value_delimiter = ',')]
+    pub drop_params: Vec<String>,
+
+    /// Sort the query parameters by name
+    #[arg(long)]
+    pub sort_params: bool,
+
+    /// Use the same scheme for both http and https URLs
+    #[arg(long, value_enum)]
+    pub unify_scheme: Option<Scheme>,
+}
+
+impl Canonicalization {
+    fn is_dropped(&self, name: &str) -> bool {
+        self.drop_params
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+
+    /// Applies the enabled steps to the URL, except for the scheme unification.
+    pub fn apply(&self, url: &mut Url) {
+        if self.strip_fragment {
+            url.set_fragment(None);
+        }
+        if !self.drop_params.is_empty() || self.sort_params {
+            let Some(query) = url.query() else {
+                return;
+            };
+            // Work on the raw pairs to keep their encoding intact
+            let mut pairs = query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let name = pair.split_once('=').map_or(pair, |(name, _)| name);
+                    (
+                        percent_decode_str(name).decode_utf8_lossy().into_owned(),
+                        pair,
+                    )
+                })
+                .filter(|(name, _)| !self.is_dropped(name))
+                .collect::<Vec<_>>();
+            if self.sort_params {
+                // Stable, so repeated parameters keep their order
+                pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+            }
+            let query = pairs
+                .iter()
+                .map(|(_, pair)| *pair)
+                .collect::<Vec<_>>()
+                .join("&");
+            url.set_query((!query.is_empty()).then_some(query.as_str()));
+        }
+    }
+
+    /// The canonical URL to derive the image path from, with the scheme unified.
+    /// Only the paths get it, as the hosts may not serve the other scheme.
+    pub fn hash_key(&self, url: &str) -> String {
+        let Some(scheme) = self.unify_scheme else {
+            return url.to_string();
+        };
+        match Url::parse(url) {
+            Ok(mut parsed) if matches!(parsed.scheme(), "http" | "https") => {
+                let scheme = match scheme {
+                    Scheme::Http => "http",
+                    Scheme::Https => "https",
+                };
+                // Only fails between special and non-special schemes
+                let _ = parsed.set_scheme(scheme);
+                parsed.to_string()
+            }
+            _ => url.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(canonicalization: &Canonicalization, url: &str) -> String {
+        let mut url = Url::parse(url).unwrap();
+        canonicalization.apply(&mut url);
+        url.to_string()
+    }
+
+    #[test]
+    fn normalizes_hosts_and_ports() {
+        let none = Canonicalization::default();
+        assert_eq!(
+            canonical(&none, "HTTP://ExAmple.COM:80/A?b=1#c"),
+            "http://example.com/A?b=1#c"
+        );
+        assert_eq!(
+            canonical(&none, "https://bücher.example:443/"),
+            "https://xn--bcher-kva.example/"
+        );
+    }
+
+    #[test]
+    fn strips_fragments() {
+        let strip = Canonicalization {
+            strip_fragment: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            canonical(&strip, "http://a.com/1.jpg#x"),
+            "http://a.com/1.jpg"
+        );
+    }
+
+    #[test]
+    fn drops_and_sorts_params() {
+        let params = Canonicalization {
+            drop_params: vec!["utm_*".to_string(), "cb".to_string()],
+            sort_params: true,
+            ..Default::default()
+        };
+        for (url, expected) in [
+            (
+                "http://a.com/?z=1&utm_source=x&a=2&cb=3&a=1",
+                "http://a.com/?a=2&a=1&z=1",
+            ),
+            ("http://a.com/?utm_%73ource=x&cbx=1", "http://a.com/?cbx=1"),
+            ("http://a.com/?utm_a=1&cb=2", "http://a.com/"),
+            ("http://a.com/?b=%2F&a=x%20y", "http://a.com/?a=x%20y&b=%2F"),
+            ("http://a.com/", "http://a.com/"),
+        ] {
+            assert_eq!(canonical(&params, url), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn unifies_scheme_of_hash_keys_only() {
+        let unify = Canonicalization {
+            unify_scheme: Some(Scheme::Https),
+            ..Default::default()
+        };
+        assert_eq!(
+            canonical(&unify, "http://a.com/1.jpg"),
+            "http://a.com/1.jpg"
+        );
+        assert_eq!(unify.hash_key("http://a.com/1.jpg"), "https://a.com/1.jpg");
+        assert_eq!(unify.hash_key("https://a.com/1.jpg"), "https://a.com/1.jpg");
+        assert_eq!(unify.hash_key("s3://bucket/1.jpg"), "s3://bucket/1.jpg");
+        assert_eq!(unify.hash_key("not a url"), "not a url");
+        let none = Canonicalization::default();
+        assert_eq!(none.hash_key("http://a.com/1.jpg"), "http://a.com/1.jpg");
+    }
+}
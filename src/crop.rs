@@ -0,0 +1,202 @@
+use std::fmt;
+
+use clap::{Args, ValueEnum};
+
+/// The order of the box coordinates.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum BoxFormat {
+    /// Left, top, width and height
+    #[default]
+    Xywh,
+    /// Left, top, right and bottom
+    Xyxy,
+}
+
+/// Where the boxes to crop the images to are found, and how they are given.
+#[derive(Args, Clone, Debug, Default)]
+pub struct Cropping {
+    /// Field indexes of the box to crop the images to, e.g. 2,3,4,5
+    #[arg(long, value_delimiter = ',')]
+    pub box_fields: Vec<i8>,
+
+    /// The order of the box coordinates
+    #[arg(long, value_enum, default_value_t)]
+    pub box_format: BoxFormat,
+
+    /// Read the box coordinates as fractions of the image size instead of pixels
+    #[arg(long)]
+    pub box_normalized: bool,
+
+    /// Padding added around the boxes, as a fraction of their size
+    #[arg(long, default_value_t = 0.0)]
+    pub box_padding: f64,
+}
+
+/// A box to crop an image to.
+#[derive(Clone, Debug)]
+pub struct Crop {
+    left: f64,
+    top: f64,
+    right: f64,
+    bottom: f64,
+    normalized: bool,
+}
+
+impl Cropping {
+    pub fn is_enabled(&self) -> bool {
+        !self.box_fields.is_empty()
+    }
+
+    /// Checks there are either no box fields or all four.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.box_fields.len() {
+            0 | 4 => Ok(()),
+            count => Err(format!("Expected 4 box fields, got {}", count)),
+        }
+    }
+
+    /// Parses the cells of the box fields, `None` meaning the whole image if all are empty.
+    pub fn parse(&self, cells: &[&str]) -> Result<Option<Crop>, String> {
+        if cells.iter().all(|cell| cell.trim().is_empty()) {
+            return Ok(None);
+        }
+        let values = cells
+            .iter()
+            .map(|cell| {
+                cell.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|value| value.is_finite())
+                    .ok_or_else(|| format!("Invalid coordinate: {:?}", cell))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let &[x, y, a, b] = values.as_slice() else {
+            return Err(format!("Expected 4 coordinates, got {}", values.len()));
+        };
+        let (right, bottom) = match self.box_format {
+            BoxFormat::Xywh => (x + a, y + b),
+            BoxFormat::Xyxy => (a, b),
+        };
+        if right <= x || bottom <= y {
+            return Err(format!("Empty box: {}", cells.join(",")));
+        }
+        let (pad_x, pad_y) = (
+            (right - x) * self.box_padding,
+            (bottom - y) * self.box_padding,
+        );
+        Ok(Some(Crop {
+            left: x - pad_x,
+            top: y - pad_y,
+            right: right + pad_x,
+            bottom: bottom + pad_y,
+            normalized: self.box_normalized,
+        }))
+    }
+}
+
+impl Crop {
+    /// The pixel region as x, y, width and height, clamped to the image, if any is left.
+    pub fn region(&self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let (scale_x, scale_y) = match self.normalized {
+            true => (width as f64, height as f64),
+            false => (1.0, 1.0),
+        };
+        let clamp = |value: f64, max: u32| value.clamp(0.0, max as f64) as u32;
+        let left = clamp((self.left * scale_x).floor(), width);
+        let top = clamp((self.top * scale_y).floor(), height);
+        let right = clamp((self.right * scale_x).ceil(), width);
+        let bottom = clamp((self.bottom * scale_y).ceil(), height);
+        (right > left && bottom > top).then(|| (left, top, right - left, bottom - top))
+    }
+}
+
+impl fmt::Display for Crop {
+    /// Identifies the crop in the image paths, e.g. `box=10,20,110,220`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}={},{},{},{}",
+            if self.normalized { "nbox" } else { "box" },
+            self.left,
+            self.top,
+            self.right,
+            self.bottom
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cropping(box_format: BoxFormat, box_normalized: bool, box_padding: f64) -> Cropping {
+        Cropping {
+            box_fields: vec![1, 2, 3, 4],
+            box_format,
+            box_normalized,
+            box_padding,
+        }
+    }
+
+    #[test]
+    fn validates_box_fields() {
+        assert!(Cropping::default().validate().is_ok());
+        assert!(cropping(BoxFormat::Xywh, false, 0.0).validate().is_ok());
+        let three = Cropping {
+            box_fields: vec![1, 2, 3],
+            ..Default::default()
+        };
+        assert!(three.validate().is_err());
+    }
+
+    #[test]
+    fn parses_boxes() {
+        let xywh = cropping(BoxFormat::Xywh, false, 0.0);
+        let crop = xywh.parse(&["10", " 20", "100", "200 "]).unwrap().unwrap();
+        assert_eq!(crop.to_string(), "box=10,20,110,220");
+        let xyxy = cropping(BoxFormat::Xyxy, false, 0.0);
+        let crop = xyxy.parse(&["10", "20", "100", "200"]).unwrap().unwrap();
+        assert_eq!(crop.to_string(), "box=10,20,100,200");
+        assert!(xywh.parse(&["", " ", "", ""]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_boxes() {
+        let xyxy = cropping(BoxFormat::Xyxy, false, 0.0);
+        for cells in [
+            ["10", "20", "", "200"],
+            ["10", "20", "x", "200"],
+            ["10", "20", "inf", "200"],
+            ["10", "20", "10", "200"],
+            ["10", "20", "5", "200"],
+        ] {
+            assert!(xyxy.parse(&cells).is_err(), "{:?}", cells);
+        }
+    }
+
+    #[test]
+    fn pads_and_clamps_regions() {
+        let padded = cropping(BoxFormat::Xywh, false, 0.5);
+        let crop = padded.parse(&["10", "10", "20", "40"]).unwrap().unwrap();
+        assert_eq!(crop.to_string(), "box=0,-10,40,70");
+        assert_eq!(crop.region(100, 100), Some((0, 0, 40, 70)));
+        assert_eq!(crop.region(30, 50), Some((0, 0, 30, 50)));
+
+        let outside = cropping(BoxFormat::Xyxy, false, 0.0);
+        let crop = outside.parse(&["200", "0", "300", "10"]).unwrap().unwrap();
+        assert_eq!(crop.region(100, 100), None);
+    }
+
+    #[test]
+    fn scales_normalized_regions() {
+        let normalized = cropping(BoxFormat::Xyxy, true, 0.0);
+        let crop = normalized
+            .parse(&["0.25", "0.5", "0.75", "1"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(crop.to_string(), "nbox=0.25,0.5,0.75,1");
+        assert_eq!(crop.region(200, 100), Some((50, 50, 100, 50)));
+        // Partial pixels are kept whole
+        assert_eq!(crop.region(3, 3), Some((0, 1, 3, 2)));
+    }
+}
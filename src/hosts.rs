@@ -0,0 +1,17 @@
+use url::Url;
+
+/// Returns the host of the URL, or an empty string if it has none.
+pub fn host_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_default()
+}
+
+/// Whether the host equals the pattern or is its subdomain.
+pub fn host_matches(host: &str, pattern: &str) -> bool {
+    host == pattern
+        || host
+            .strip_suffix(pattern)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
@@ -1,12 +1,65 @@
-use std::path::PathBuf;
+use std::{
+    path::{self, Path, PathBuf},
+    str::FromStr,
+};
 
+use clap::ValueEnum;
 use thiserror::Error;
 use url::{ParseError, Url};
 
+use crate::{
+    canonical::Canonicalization,
+    crop::{Crop, Cropping},
+    rewrite::RewriteRules,
+};
+
+/// The URL schemes the items may be fetched with.
+pub const SUPPORTED_SCHEMES: [&str; 5] = ["http", "https", "s3", "file", "data"];
+
+#[derive(Clone)]
 pub struct Item {
     pub id: String,
     pub url: String,
+    pub fallbacks: Vec<String>,
+    /// The position in the gallery the URL comes from, if any
+    pub position: Option<usize>,
     pub path: PathBuf,
+    /// The box to crop the image to, if any
+    pub crop: Option<Crop>,
+    /// Where the image as a whole goes when the records only keep parts of it
+    pub original: Option<PathBuf>,
+}
+
+/// Which URL the image paths are derived from when rewrite rules change it.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum HashUrl {
+    /// The URL found in the source, so existing output trees stay valid
+    #[default]
+    Original,
+    /// The URL after the rewrite rules
+    Rewritten,
+}
+
+/// How a URL field holds a whole gallery of images.
+#[derive(Clone, Debug)]
+pub enum Gallery {
+    /// URLs separated by a delimiter
+    Delimited(String),
+    /// A JSON array of URLs
+    Json,
+}
+
+impl FromStr for Gallery {
+    type Err = String;
+
+    /// Parses `json` or the delimiter, e.g. `|`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("Gallery delimiter can't be empty".to_string()),
+            "json" => Ok(Gallery::Json),
+            delimiter => Ok(Gallery::Delimited(delimiter.to_string())),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -14,6 +67,18 @@ pub enum ParsingError {
     #[error("URL parsing error: {0}")]
     UrlParse(url::ParseError),
 
+    #[error("Unsupported URL scheme: {0}")]
+    UnsupportedScheme(String),
+
+    #[error("Invalid local path: {0}")]
+    InvalidPath(String),
+
+    #[error("Gallery parsing error: {0}")]
+    Gallery(String),
+
+    #[error("Crop box parsing error: {0}")]
+    Crop(String),
+
     #[error("Record parsing error: {0}")]
     Custom(String),
 }
@@ -49,8 +114,48 @@ fn extract_url(record: &csv::StringRecord, url_field: i8) -> Result<String, Pars
         .map(|s| s.to_string())
 }
 
-fn normalize_url(url: &str) -> Result<String, ParseError> {
-    Url::parse(url).map(|parsed| parsed.to_string())
+fn split_gallery(cell: &str, gallery: &Gallery) -> Result<Vec<String>, ParsingError> {
+    match gallery {
+        Gallery::Delimited(delimiter) => {
+            Ok(cell.split(delimiter.as_str()).map(str::to_string).collect())
+        }
+        Gallery::Json if cell.trim().is_empty() => Ok(Vec::new()),
+        Gallery::Json => serde_json::from_str::<Vec<String>>(cell)
+            .map_err(|e| ParsingError::Gallery(e.to_string())),
+    }
+}
+
+fn path_to_url(path: &str) -> Result<Url, ParsingError> {
+    path::absolute(path)
+        .ok()
+        .and_then(|path| Url::from_file_path(path).ok())
+        .ok_or_else(|| ParsingError::InvalidPath(path.to_string()))
+}
+
+/// Whether the value is clearly meant as a local path rather than a broken
+/// URL or a placeholder like `n/a`.
+fn is_local_path(url: &str) -> bool {
+    // Protocol-relative URLs look like absolute paths
+    !url.starts_with("//")
+        && (Path::new(url).is_absolute()
+            || url.starts_with("./")
+            || url.starts_with("../")
+            || url.starts_with(".\\")
+            || url.starts_with("..\\"))
+}
+
+fn normalize_url(url: &str) -> Result<Url, ParsingError> {
+    let parsed = match Url::parse(url) {
+        // A single letter scheme is a Windows drive
+        Ok(parsed) if parsed.scheme().len() == 1 => path_to_url(url)?,
+        Ok(parsed) => parsed,
+        Err(ParseError::RelativeUrlWithoutBase) if is_local_path(url) => path_to_url(url)?,
+        Err(e) => return Err(ParsingError::UrlParse(e)),
+    };
+    if !SUPPORTED_SCHEMES.contains(&parsed.scheme()) {
+        return Err(ParsingError::UnsupportedScheme(parsed.scheme().to_string()));
+    }
+    Ok(parsed)
 }
 
 fn url_to_path(url: &str, output_root: &str, extension: &str) -> PathBuf {
@@ -63,6 +168,7 @@ fn url_to_path(url: &str, output_root: &str, extension: &str) -> PathBuf {
 }
 
 impl Item {
+    /// Parses the record with a single URL field and the default options.
     pub fn from_record(
         record: &csv::StringRecord,
         fields: &[i8],
@@ -70,17 +176,310 @@ impl Item {
         output_root: &str,
         extension: &str,
     ) -> Result<Self, ParsingError> {
-        let item_id = match fields.len() {
+        let parser = ItemParser {
+            fields: fields.to_vec(),
+            url_fields: vec![url_field],
+            path_field: None,
+            gallery: None,
+            canonical: Canonicalization::default(),
+            cropping: Cropping::default(),
+            rewrites: RewriteRules::default(),
+            hash_url: HashUrl::default(),
+            output_root: output_root.to_string(),
+            originals_root: None,
+            extension: extension.to_string(),
+        };
+        // Without galleries there's exactly one item per record
+        parser.parse(record).remove(0)
+    }
+
+    /// The primary URL followed by the fallbacks.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.url.as_str()).chain(self.fallbacks.iter().map(String::as_str))
+    }
+}
+
+/// How the records of a source file are turned into items.
+pub struct ItemParser {
+    pub fields: Vec<i8>,
+    /// URL fields in order of preference, the first usable one being fetched first.
+    /// The paths are derived from the first field, unless `path_field` is set.
+    pub url_fields: Vec<i8>,
+    /// The field to derive the path from instead of the primary URL, e.g. a stable key
+    pub path_field: Option<i8>,
+    /// How to split the URL fields into galleries, if they hold them
+    pub gallery: Option<Gallery>,
+    pub canonical: Canonicalization,
+    pub cropping: Cropping,
+    pub rewrites: RewriteRules,
+    pub hash_url: HashUrl,
+    pub output_root: String,
+    /// The root to save the images shared by several records once, if any
+    pub originals_root: Option<String>,
+    pub extension: String,
+}
+
+impl ItemParser {
+    /// Parses the record into its items, one per gallery position if galleries are enabled.
+    pub fn parse(&self, record: &csv::StringRecord) -> Vec<Result<Item, ParsingError>> {
+        match self.parse_positions(record) {
+            Ok(items) => items,
+            Err(e) => vec![Err(e)],
+        }
+    }
+
+    fn canonicalize(&self, url: &str) -> Result<String, ParsingError> {
+        let mut url = normalize_url(url)?;
+        self.canonical.apply(&mut url);
+        Ok(url.to_string())
+    }
+
+    /// Canonicalizes and rewrites the URL, returning both the original and the rewritten one.
+    fn resolve(&self, url: &str) -> Result<(String, String), ParsingError> {
+        let original = self.canonicalize(url)?;
+        let rewritten = self.rewrites.apply(&original);
+        if rewritten == original {
+            return Ok((original.clone(), original));
+        }
+        Ok((original, self.canonicalize(&rewritten)?))
+    }
+
+    fn parse_positions(
+        &self,
+        record: &csv::StringRecord,
+    ) -> Result<Vec<Result<Item, ParsingError>>, ParsingError> {
+        let item_id = match self.fields.len() {
             0 => "n/a".to_string(),
-            _ => extract_item_id(record, fields)?,
+            _ => extract_item_id(record, &self.fields)?,
         };
-        let url = extract_url(record, url_field)?;
-        let normalized = normalize_url(&url).map_err(ParsingError::UrlParse)?;
-        let path = url_to_path(&normalized, output_root, extension);
-        Ok(Item {
-            id: item_id,
-            url: normalized,
-            path,
-        })
+        let columns = self
+            .url_fields
+            .iter()
+            .map(|&url_field| {
+                let cell = extract_url(record, url_field)?;
+                match &self.gallery {
+                    Some(gallery) => split_gallery(&cell, gallery),
+                    None => Ok(vec![cell]),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // Any stable key will do, so the field doesn't have to hold a URL
+        let key = match self.path_field {
+            Some(path_field) => {
+                let key = extract_url(record, path_field)?;
+                Some(match Url::parse(&key) {
+                    Ok(mut parsed) => {
+                        self.canonical.apply(&mut parsed);
+                        self.canonical.hash_key(parsed.as_str())
+                    }
+                    Err(_) => key.trim().to_string(),
+                })
+            }
+            None => None,
+        };
+        let crop = if self.cropping.is_enabled() {
+            let cells = self
+                .cropping
+                .box_fields
+                .iter()
+                .map(|&field| extract_url(record, field))
+                .collect::<Result<Vec<_>, _>>()?;
+            let cells = cells.iter().map(String::as_str).collect::<Vec<_>>();
+            self.cropping.parse(&cells).map_err(ParsingError::Crop)?
+        } else {
+            None
+        };
+
+        let count = columns.iter().map(Vec::len).max().unwrap_or_default();
+        let mut items = Vec::new();
+        for position in 0..count {
+            let mut urls = Vec::<String>::new();
+            let mut originals = Vec::<String>::new();
+            let mut error = None;
+            let mut primary_resolved = false;
+            for (index, column) in columns.iter().enumerate() {
+                let Some(cell) = column.get(position) else {
+                    continue;
+                };
+                // Galleries may have gaps, while a single URL is required
+                if self.gallery.is_some() && cell.trim().is_empty() {
+                    continue;
+                }
+                match self.resolve(cell) {
+                    Ok((original, url)) if !urls.contains(&url) => {
+                        primary_resolved |= index == 0;
+                        originals.push(original);
+                        urls.push(url);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+            if urls.is_empty() {
+                if let Some(e) = error {
+                    items.push(Err(e));
+                }
+                continue;
+            }
+            let primary = columns[0].get(position).map(|cell| cell.trim());
+            let position = self.gallery.is_some().then_some(position);
+            let hashed = self.canonical.hash_key(match self.hash_url {
+                HashUrl::Original => &originals[0],
+                HashUrl::Rewritten => &urls[0],
+            });
+            let original = self
+                .originals_root
+                .as_ref()
+                .map(|root| url_to_path(&hashed, root, &self.extension));
+            let path = match (&key, position) {
+                (Some(key), Some(position)) => format!("{}#{}", key, position),
+                (Some(key), None) => key.clone(),
+                (None, _) if primary_resolved => hashed,
+                // Keep the path of the record when the primary URL doesn't parse
+                (None, _) => match primary {
+                    Some(cell) if !cell.is_empty() => cell.to_string(),
+                    _ => {
+                        items.push(Err(ParsingError::Custom(
+                            "Primary URL field is empty".to_string(),
+                        )));
+                        continue;
+                    }
+                },
+            };
+            // Crops of the same image need paths of their own
+            let path = match &crop {
+                Some(crop) => format!("{}#{}", path, crop),
+                None => path,
+            };
+            items.push(Ok(Item {
+                id: item_id.clone(),
+                url: urls.remove(0),
+                fallbacks: urls,
+                position,
+                path: url_to_path(&path, &self.output_root, &self.extension),
+                crop: crop.clone(),
+                original,
+            }));
+        }
+        if items.is_empty() && self.gallery.is_none() {
+            items.push(Err(ParsingError::Custom("No URL fields".to_string())));
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_local_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        let url = normalize_url("./images/a.jpg").unwrap();
+        assert_eq!(url.scheme(), "file");
+        assert_eq!(url.to_file_path().unwrap(), cwd.join("images/a.jpg"));
+        assert_eq!(normalize_url("../a.jpg").unwrap().scheme(), "file");
+        #[cfg(unix)]
+        assert_eq!(
+            normalize_url("/data/a.jpg").unwrap().as_str(),
+            "file:///data/a.jpg"
+        );
+    }
+
+    fn parser(path_field: Option<i8>) -> ItemParser {
+        ItemParser {
+            fields: vec![0],
+            url_fields: vec![1, 2],
+            path_field,
+            gallery: None,
+            canonical: Canonicalization::default(),
+            cropping: Cropping::default(),
+            rewrites: RewriteRules::default(),
+            hash_url: HashUrl::Original,
+            output_root: "out".to_string(),
+            originals_root: None,
+            extension: "webp".to_string(),
+        }
+    }
+
+    fn parse(parser: &ItemParser, fields: &[&str]) -> Result<Item, ParsingError> {
+        let mut items = parser.parse(&csv::StringRecord::from(fields.to_vec()));
+        assert_eq!(items.len(), 1);
+        items.remove(0)
+    }
+
+    #[test]
+    fn derives_path_from_primary_url() {
+        let parser = parser(None);
+        let item = parse(&parser, &["1", "http://a.com/1.jpg", "http://b.com/1.jpg"]).unwrap();
+        assert_eq!(item.url, "http://a.com/1.jpg");
+        assert_eq!(item.fallbacks, ["http://b.com/1.jpg"]);
+        assert_eq!(item.path, url_to_path("http://a.com/1.jpg", "out", "webp"));
+    }
+
+    #[test]
+    fn parses_single_url_records() {
+        let record = csv::StringRecord::from(vec!["1", "x", "http://a.com/1.jpg"]);
+        let item = Item::from_record(&record, &[0, 1], -1, "out", "webp").unwrap();
+        assert_eq!(item.id, "1$x");
+        assert_eq!(item.url, "http://a.com/1.jpg");
+        assert!(item.fallbacks.is_empty());
+        assert_eq!(item.path, url_to_path("http://a.com/1.jpg", "out", "webp"));
+        let record = csv::StringRecord::from(vec!["1", "n/a"]);
+        assert!(Item::from_record(&record, &[0], 1, "out", "webp").is_err());
+    }
+
+    #[test]
+    fn keeps_path_of_unparseable_primary_url() {
+        let parser = parser(None);
+        let item = parse(&parser, &["1", "n/a", "http://b.com/1.jpg"]).unwrap();
+        assert_eq!(item.url, "http://b.com/1.jpg");
+        assert_eq!(item.path, url_to_path("n/a", "out", "webp"));
+        assert!(parse(&parser, &["1", " ", "http://b.com/1.jpg"]).is_err());
+    }
+
+    #[test]
+    fn unifies_scheme_of_path_only() {
+        let mut parser = parser(None);
+        parser.canonical.unify_scheme = Some(crate::canonical::Scheme::Https);
+        let item = parse(&parser, &["1", "http://a.com/1.jpg", ""]).unwrap();
+        assert_eq!(item.url, "http://a.com/1.jpg");
+        assert_eq!(item.path, url_to_path("https://a.com/1.jpg", "out", "webp"));
+    }
+
+    #[test]
+    fn derives_path_from_path_field() {
+        let parser = parser(Some(3));
+        let item = parse(&parser, &["1", "", "http://b.com/1.jpg", "key"]).unwrap();
+        assert_eq!(item.url, "http://b.com/1.jpg");
+        assert_eq!(item.path, url_to_path("key", "out", "webp"));
+    }
+
+    #[test]
+    fn rejects_unsupported_schemes() {
+        for url in ["ftp://a.com/1.jpg", "mailto:a@b.com", "javascript:void(0)"] {
+            assert!(
+                matches!(normalize_url(url), Err(ParsingError::UnsupportedScheme(_))),
+                "{}",
+                url
+            );
+        }
+        for url in [
+            "s3://bucket/1.jpg",
+            "data:,1",
+            "file:///1.jpg",
+            "HTTPS://a.com/",
+        ] {
+            assert!(normalize_url(url).is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn rejects_bare_values() {
+        for value in ["n/a", "none", "images/a.jpg", "//cdn.example.com/a.jpg", ""] {
+            assert!(normalize_url(value).is_err(), "{:?}", value);
+        }
     }
 }
@@ -1,4 +1,8 @@
+pub mod canonical;
+pub mod crop;
+pub mod hosts;
 pub mod item;
+pub mod rewrite;
 pub mod rslc;
 
 use std::fmt::Write;
@@ -6,7 +10,7 @@ use std::fmt::Write;
 use indicatif::{ProgressBar, ProgressFinish, ProgressState, ProgressStyle};
 
 const BAR_TEMPLATE: &str =
-    "{percent:>3}% |{wide_bar}| {human_pos}/{human_len} [{elapsed}<{eta}, {my_per_sec}]";
+    "{percent:>3}% |{wide_bar}| {human_pos}/{human_len} [{elapsed}<{eta}, {my_per_sec}{msg}]";
 
 pub fn maybe_create_progressbar(progress: bool, len: u64) -> Option<ProgressBar> {
     if progress {
@@ -0,0 +1,173 @@
+use std::{fs, io, path::Path};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::hosts::{host_matches, host_of};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    host: Option<String>,
+    search: String,
+    replace: String,
+}
+
+/// A regex search and replace, optionally for a host and its subdomains only.
+struct Rule {
+    host: Option<String>,
+    search: Regex,
+    replace: String,
+}
+
+/// URL rewrite rules applied in order, each to the result of the previous ones.
+#[derive(Default)]
+pub struct RewriteRules {
+    rules: Vec<Rule>,
+}
+
+impl RewriteRules {
+    /// Loads the rules from a TOML file with a `[[rules]]` table per rule.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |e: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Error parsing rewrite rules {}: {}", path.display(), e),
+            )
+        };
+        let content = fs::read_to_string(path)?;
+        let file = toml::from_str::<RulesFile>(&content).map_err(|e| invalid(e.to_string()))?;
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|rule| {
+                Ok(Rule {
+                    host: rule.host.map(|host| host.to_lowercase()),
+                    search: Regex::new(&rule.search).map_err(|e| invalid(e.to_string()))?,
+                    replace: rule.replace,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(RewriteRules { rules })
+    }
+
+    /// Loads the rules if a path is given.
+    pub fn maybe_load(path: Option<&Path>) -> io::Result<Self> {
+        path.map_or_else(|| Ok(RewriteRules::default()), RewriteRules::load)
+    }
+
+    /// Applies the matching rules to the URL, replacing all the matches.
+    pub fn apply(&self, url: &str) -> String {
+        self.rules.iter().fold(url.to_string(), |url, rule| {
+            let applies = rule
+                .host
+                .as_deref()
+                .is_none_or(|host| host_matches(&host_of(&url), host));
+            if applies {
+                rule.search
+                    .replace_all(&url, rule.replace.as_str())
+                    .into_owned()
+            } else {
+                url
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, content: &str) -> io::Result<RewriteRules> {
+        let path = std::env::temp_dir().join(format!(
+            "rskachka-rewrite-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, content)?;
+        let rules = RewriteRules::load(&path);
+        fs::remove_file(&path)?;
+        rules
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let rules = load(
+            "order",
+            r#"
+            [[rules]]
+            search = '_s\.jpg$'
+            replace = '_m.jpg'
+
+            [[rules]]
+            search = '_m\.jpg$'
+            replace = '_l.jpg'
+
+            [[rules]]
+            search = '([?&])w=\d+'
+            replace = '${1}w=1024'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules.apply("http://a.com/1_s.jpg"), "http://a.com/1_l.jpg");
+        assert_eq!(
+            rules.apply("http://a.com/1.jpg?w=150&h=1&w=2"),
+            "http://a.com/1.jpg?w=1024&h=1&w=1024"
+        );
+        assert_eq!(rules.apply("http://a.com/1.png"), "http://a.com/1.png");
+    }
+
+    #[test]
+    fn scopes_rules_to_hosts() {
+        let rules = load(
+            "hosts",
+            r#"
+            [[rules]]
+            host = "CDN.example.com"
+            search = '/thumbs/'
+            replace = '/full/'
+            "#,
+        )
+        .unwrap();
+        for (url, expected) in [
+            (
+                "http://cdn.example.com/thumbs/1.jpg",
+                "http://cdn.example.com/full/1.jpg",
+            ),
+            (
+                "http://eu.cdn.example.com/thumbs/1.jpg",
+                "http://eu.cdn.example.com/full/1.jpg",
+            ),
+            (
+                "http://example.com/thumbs/1.jpg",
+                "http://example.com/thumbs/1.jpg",
+            ),
+            (
+                "http://notcdn.example.com/thumbs/1.jpg",
+                "http://notcdn.example.com/thumbs/1.jpg",
+            ),
+        ] {
+            assert_eq!(rules.apply(url), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let invalid = "[[rules]]\nsearch = '('\nreplace = ''\n";
+        assert!(load("regex", invalid).is_err());
+        let unknown = "[[rules]]\nsearch = 'a'\nreplace = 'b'\nhsot = 'a.com'\n";
+        assert!(load("unknown", unknown).is_err());
+        assert_eq!(
+            load("empty", "").unwrap().apply("http://a.com/"),
+            "http://a.com/"
+        );
+        assert_eq!(RewriteRules::maybe_load(None).unwrap().apply("x"), "x");
+    }
+}
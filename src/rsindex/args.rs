@@ -1,4 +1,11 @@
+use std::path::PathBuf;
+
 use clap::Parser;
+use rskachka::{
+    canonical::Canonicalization,
+    crop::Cropping,
+    item::{Gallery, HashUrl},
+};
 
 const DEFAULT_EXTENSION: &str = "webp";
 
@@ -25,9 +32,33 @@ pub struct Args {
     #[arg(short, long, default_value_t = DEFAULT_EXTENSION.to_string())]
     pub extension: String,
 
-    /// URL field index
-    #[arg(short, long, default_value_t = -1)]
-    pub url_field: i8,
+    /// URL field indexes, the ones after the first being tried as fallbacks
+    #[arg(short, long, value_delimiter = ',', default_values_t = [-1])]
+    pub url_field: Vec<i8>,
+
+    /// Field index to derive the image path from instead of the primary URL
+    #[arg(long)]
+    pub path_field: Option<i8>,
+
+    /// Split URL fields into galleries by a delimiter like '|', or 'json' for JSON arrays
+    #[arg(long)]
+    pub gallery: Option<Gallery>,
+
+    /// URL canonicalization
+    #[command(flatten)]
+    pub canonical: Canonicalization,
+
+    /// Crop boxes
+    #[command(flatten)]
+    pub cropping: Cropping,
+
+    /// TOML file of regex rules rewriting the URLs before fetching
+    #[arg(long)]
+    pub rewrite_rules: Option<PathBuf>,
+
+    /// URL to derive the image paths from when the rules rewrite it
+    #[arg(long, value_enum, default_value_t = HashUrl::Original)]
+    pub hash_url: HashUrl,
 
     /// Concurrent workers count
     #[arg(short, long, default_value_t = num_cpus::get() * 2)]
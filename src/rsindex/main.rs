@@ -1,13 +1,16 @@
 mod args;
 
-use std::{fs::File, thread};
+use std::{
+    fs::File,
+    thread::{self, JoinHandle},
+};
 
 use clap::Parser;
 use crossbeam::channel::{bounded, Receiver, Sender};
 use indicatif::ProgressBar;
 use log::{error, warn};
 use memmap2::Mmap;
-use rskachka::{item::Item, maybe_create_progressbar, rslc};
+use rskachka::{item::ItemParser, maybe_create_progressbar, rewrite::RewriteRules, rslc};
 
 use crate::args::Args;
 
@@ -53,7 +56,7 @@ fn launch_saver(
     index_writer: csv::Writer<File>,
     missing_writer: Option<csv::Writer<File>>,
     save_rx: Receiver<OutputRecord>,
-) {
+) -> JoinHandle<()> {
     thread::Builder::new()
         .name("saver".to_string())
         .spawn(move || {
@@ -72,13 +75,11 @@ fn launch_saver(
                 }
             }
         })
-        .unwrap();
+        .unwrap()
 }
 
 fn launch_workers(
-    url_field: i8,
-    output_root: &str,
-    extension: &str,
+    parser: &ItemParser,
     work_rx: &Receiver<InputRecord>,
     save_tx: &Sender<OutputRecord>,
 ) {
@@ -87,29 +88,47 @@ fn launch_workers(
             thread::Builder::new()
                 .name(format!("worker{}", i))
                 .spawn_scoped(s, move || {
-                    while let Ok(mut record) = work_rx.recv() {
-                        match Item::from_record(&record, &[0], url_field, output_root, extension) {
-                            Ok(item) => {
-                                if item.path.exists() {
-                                    record.extend([item.path.to_str().unwrap()]);
-                                    save_tx
-                                        .send(OutputRecord {
-                                            record,
-                                            found: true,
-                                        })
-                                        .unwrap();
-                                } else {
-                                    save_tx
-                                        .send(OutputRecord {
-                                            record,
-                                            found: false,
-                                        })
-                                        .unwrap();
+                    while let Ok(record) = work_rx.recv() {
+                        let mut found = Vec::new();
+                        let mut missing = false;
+                        for item in parser.parse(&record) {
+                            let item = match item {
+                                Ok(item) => item,
+                                Err(e) => {
+                                    warn!("Error parsing record: {}", e);
+                                    continue;
                                 }
+                            };
+                            if !item.path.exists() {
+                                missing = true;
+                                continue;
                             }
-                            Err(e) => {
-                                warn!("Error parsing record: {}", e);
+                            // One row per image, telling gallery images apart
+                            let mut record = record.clone();
+                            if let Some(position) = item.position {
+                                record.extend([position.to_string()]);
                             }
+                            record.extend([item.path.to_str().unwrap()]);
+                            found.push(record);
+                        }
+                        // Records with any image missing are listed as they are, to be
+                        // passed back to rskachka, and indexed only once all are found
+                        if missing {
+                            save_tx
+                                .send(OutputRecord {
+                                    record,
+                                    found: false,
+                                })
+                                .unwrap();
+                            continue;
+                        }
+                        for record in found {
+                            save_tx
+                                .send(OutputRecord {
+                                    record,
+                                    found: true,
+                                })
+                                .unwrap();
                         }
                     }
                 })
@@ -120,6 +139,19 @@ fn launch_workers(
 
 pub fn main() -> std::io::Result<()> {
     let args = Args::parse();
+    if let Err(e) = args.cropping.validate() {
+        error!("{}", e);
+        return Ok(());
+    }
+
+    // Load the rewrite rules
+    let rewrites = match RewriteRules::maybe_load(args.rewrite_rules.as_deref()) {
+        Ok(rewrites) => rewrites,
+        Err(e) => {
+            error!("Error loading rewrite rules: {}", e);
+            return Ok(());
+        }
+    };
 
     // Open the source file and count lines
     let source_file = match File::open(&args.source_path) {
@@ -178,13 +210,16 @@ pub fn main() -> std::io::Result<()> {
 
     // Write the headers if any
     if let Some(header) = header {
-        if missing_writer.is_some() {
-            if let Err(e) = missing_writer.as_mut().unwrap().write_record(header.iter()) {
+        if let Some(missing_writer) = &mut missing_writer {
+            if let Err(e) = missing_writer.write_record(header.iter()) {
                 error!("Error adding missing header: {}", e);
                 return Ok(());
             }
         }
         let mut header = header.to_owned();
+        if args.gallery.is_some() {
+            header.extend(["image_position"]);
+        }
         header.extend(["image_path"]);
         if let Err(e) = index_writer.write_record(header.iter()) {
             error!("Error adding index header: {}", e);
@@ -203,16 +238,27 @@ pub fn main() -> std::io::Result<()> {
     launch_producer(source_reader, work_tx, &pb);
 
     // Launch the saver
-    launch_saver(index_writer, missing_writer, save_rx);
+    let saver = launch_saver(index_writer, missing_writer, save_rx);
 
     // Launch the workers
-    launch_workers(
-        args.url_field,
-        &args.output_root,
-        &args.extension,
-        &work_rx,
-        &save_tx,
-    );
+    let parser = ItemParser {
+        fields: vec![0],
+        url_fields: args.url_field,
+        path_field: args.path_field,
+        gallery: args.gallery.clone(),
+        canonical: args.canonical,
+        cropping: args.cropping,
+        rewrites,
+        hash_url: args.hash_url,
+        output_root: args.output_root,
+        originals_root: None,
+        extension: args.extension,
+    };
+    launch_workers(&parser, &work_rx, &save_tx);
+
+    // Let the saver flush the writers
+    drop(save_tx);
+    saver.join().expect("Saver thread panicked");
 
     Ok(())
 }
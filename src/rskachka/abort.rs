@@ -1,3 +1,12 @@
+use std::{
+    cmp::min,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 macro_rules! break_on_flag {
     ($atomic:expr, $closure:expr) => {{
         if $atomic.load(std::sync::atomic::Ordering::Relaxed) {
@@ -9,12 +18,56 @@ macro_rules! break_on_flag {
 }
 macro_rules! return_on_flag {
     ($atomic:expr, $closure:expr) => {{
+        return_on_flag!($atomic, $closure, ())
+    }};
+    ($atomic:expr, $closure:expr, $value:expr) => {{
         if $atomic.load(std::sync::atomic::Ordering::Relaxed) {
             #[allow(clippy::redundant_closure_call)]
             $closure();
-            return Ok(());
+            return Ok($value);
         }
     }};
 }
 pub(crate) use break_on_flag;
 pub(crate) use return_on_flag;
+
+/// Sleeps for the given duration, waking up early if the flag gets set.
+/// Returns `false` if the sleep was interrupted.
+pub fn sleep_unless_stopped(duration: Duration, stopped: &AtomicBool) -> bool {
+    // Too long to be represented means sleeping until stopped
+    let deadline = Instant::now().checked_add(duration);
+    loop {
+        if stopped.load(Ordering::Relaxed) {
+            return false;
+        }
+        let left = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => POLL_INTERVAL,
+        };
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(min(left, POLL_INTERVAL));
+    }
+}
+
+/// Sleeps without blocking the thread, waking up early if the flag gets set.
+/// Returns `false` if the sleep was interrupted.
+#[cfg(feature = "async")]
+pub async fn sleep_unless_stopped_async(duration: Duration, stopped: &AtomicBool) -> bool {
+    // Too long to be represented means sleeping until stopped
+    let deadline = Instant::now().checked_add(duration);
+    loop {
+        if stopped.load(Ordering::Relaxed) {
+            return false;
+        }
+        let left = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => POLL_INTERVAL,
+        };
+        if left.is_zero() {
+            return true;
+        }
+        tokio::time::sleep(min(left, POLL_INTERVAL)).await;
+    }
+}
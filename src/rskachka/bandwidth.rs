@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{abort::sleep_unless_stopped, hosts::host_matches, rate::TokenBucket, units::ByteSize};
+
+/// Reads at most this many bytes at once when throttled, so the waits stay short.
+const CHUNK_SIZE: usize = 16 << 10;
+
+/// A bandwidth per second applied to a host and its subdomains.
+#[derive(Clone, Debug)]
+pub struct HostBandwidth {
+    pub host: String,
+    pub bandwidth: ByteSize,
+}
+
+impl FromStr for HostBandwidth {
+    type Err = String;
+
+    /// Parses rules like `cdn.example.com=2M`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, bandwidth) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected HOST=SIZE, got {:?}", s))?;
+        let bandwidth = bandwidth.parse::<ByteSize>()?;
+        if bandwidth.0 == 0 {
+            return Err(format!("Bandwidth must be positive: {:?}", s));
+        }
+        Ok(HostBandwidth {
+            host: host.trim().to_lowercase(),
+            bandwidth,
+        })
+    }
+}
+
+/// Download bandwidth limiter shared by all workers, overall and per host,
+/// which also measures the throughput of the run.
+pub struct BandwidthLimiter {
+    total: Option<Mutex<TokenBucket>>,
+    rules: Vec<HostBandwidth>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    received: AtomicU64,
+    started: Instant,
+}
+
+impl BandwidthLimiter {
+    /// Creates a limiter, zero total bandwidth meaning no limit.
+    pub fn new(total: ByteSize, rules: Vec<HostBandwidth>) -> Self {
+        BandwidthLimiter {
+            total: (total.0 > 0).then(|| Mutex::new(TokenBucket::new(total.0 as f64))),
+            rules,
+            buckets: Mutex::new(HashMap::new()),
+            received: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.total.is_some() || !self.rules.is_empty()
+    }
+
+    /// Accounts for the bytes received from the host, returning how long
+    /// to wait before reading more to stay under the limits.
+    pub fn reserve(&self, host: &str, bytes: usize) -> Duration {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+        let mut delay = match &self.total {
+            Some(bucket) => bucket.lock().unwrap().reserve(bytes as f64),
+            None => Duration::ZERO,
+        };
+        if let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| host_matches(host, &rule.host))
+        {
+            let host_delay = self
+                .buckets
+                .lock()
+                .unwrap()
+                .entry(rule.host.clone())
+                .or_insert_with(|| TokenBucket::new(rule.bandwidth.0 as f64))
+                .reserve(bytes as f64);
+            delay = delay.max(host_delay);
+        }
+        delay
+    }
+
+    /// Accounts for the bytes received from the host, blocking for as long
+    /// as it takes to stay under the limits. Returns early if the flag gets set.
+    pub fn consume(&self, host: &str, bytes: usize, stopped: &AtomicBool) {
+        let delay = self.reserve(host, bytes);
+        if delay > Duration::ZERO {
+            sleep_unless_stopped(delay, stopped);
+        }
+    }
+
+    /// The total number of bytes received.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// The average number of bytes received per second since the start.
+    pub fn throughput(&self) -> f64 {
+        self.received() as f64 / self.started.elapsed().as_secs_f64().max(1e-3)
+    }
+}
+
+/// Passes the bytes read from a host through the limiter.
+pub struct ThrottledReader<'a, R> {
+    pub inner: R,
+    pub limiter: &'a BandwidthLimiter,
+    pub host: &'a str,
+    pub stopped: &'a AtomicBool,
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = match self.limiter.is_limited() {
+            true => buf.len().min(CHUNK_SIZE),
+            false => buf.len(),
+        };
+        let read = self.inner.read(&mut buf[..len])?;
+        self.limiter.consume(self.host, read, self.stopped);
+        Ok(read)
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+enum Circuit {
+    /// Requests go through, counting the consecutive failures
+    Closed(u32),
+    /// Requests fail fast until the cool-down ends
+    Open(Instant),
+    /// A single probe request is in flight
+    HalfOpen,
+}
+
+/// Suspends the hosts which keep failing to connect or time out, so their
+/// records fail fast instead of waiting for the timeouts. Once the cool-down
+/// ends, a single request probes whether the host is back.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    suspended: AtomicUsize,
+}
+
+impl CircuitBreaker {
+    /// Creates a breaker, a zero threshold meaning hosts are never suspended.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+            suspended: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether a request to the host may go through now. Once the cool-down
+    /// ends, the first caller gets to probe the host and must report back.
+    pub fn allow(&self, host: &str) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        match circuits.get(host) {
+            None | Some(Circuit::Closed(_)) => true,
+            Some(Circuit::Open(until)) if Instant::now() >= *until => {
+                info!("Probing {}", host);
+                circuits.insert(host.to_string(), Circuit::HalfOpen);
+                true
+            }
+            Some(Circuit::Open(_) | Circuit::HalfOpen) => false,
+        }
+    }
+
+    /// Reports whether the host could be reached.
+    pub fn record(&self, host: &str, reached: bool) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(host.to_string())
+            .or_insert(Circuit::Closed(0));
+        let was_suspended = !matches!(circuit, Circuit::Closed(_));
+        *circuit = match (&*circuit, reached) {
+            (Circuit::HalfOpen, true) => {
+                info!("Resuming {}", host);
+                Circuit::Closed(0)
+            }
+            (_, true) => Circuit::Closed(0),
+            (Circuit::Closed(failures), false) if failures + 1 < self.threshold => {
+                Circuit::Closed(failures + 1)
+            }
+            (Circuit::Closed(_) | Circuit::HalfOpen, false) => {
+                warn!("Suspending {} for {:?}", host, self.cooldown);
+                Circuit::Open(Instant::now() + self.cooldown)
+            }
+            // Failures of the requests which were in flight when it opened
+            (Circuit::Open(until), false) => Circuit::Open(*until),
+        };
+        match (was_suspended, matches!(circuit, Circuit::Closed(_))) {
+            (false, false) => self.suspended.fetch_add(1, Ordering::Relaxed),
+            (true, true) => self.suspended.fetch_sub(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    /// The number of hosts currently suspended.
+    pub fn suspended(&self) -> usize {
+        self.suspended.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, HOUR);
+        breaker.record("a", false);
+        breaker.record("a", false);
+        breaker.record("a", true);
+        breaker.record("a", false);
+        breaker.record("a", false);
+        assert!(breaker.allow("a"));
+        assert_eq!(breaker.suspended(), 0);
+
+        breaker.record("a", false);
+        assert!(!breaker.allow("a"));
+        assert!(breaker.allow("b"));
+        assert_eq!(breaker.suspended(), 1);
+
+        // Failures of the requests in flight keep it open
+        breaker.record("a", false);
+        assert!(!breaker.allow("a"));
+        assert_eq!(breaker.suspended(), 1);
+    }
+
+    #[test]
+    fn closes_after_successful_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record("a", false);
+        assert_eq!(breaker.suspended(), 1);
+
+        // A single probe goes through once the cool-down ends
+        assert!(breaker.allow("a"));
+        assert!(!breaker.allow("a"));
+        breaker.record("a", true);
+        assert!(breaker.allow("a"));
+        assert!(breaker.allow("a"));
+        assert_eq!(breaker.suspended(), 0);
+    }
+
+    #[test]
+    fn reopens_after_failed_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record("a", false);
+        assert!(breaker.allow("a"));
+        breaker.record("a", false);
+        assert_eq!(breaker.suspended(), 1);
+
+        // Probed again after another cool-down
+        assert!(breaker.allow("a"));
+        assert!(!breaker.allow("a"));
+    }
+
+    #[test]
+    fn never_opens_without_threshold() {
+        let breaker = CircuitBreaker::new(0, HOUR);
+        for _ in 0..10 {
+            breaker.record("a", false);
+        }
+        assert!(breaker.allow("a"));
+        assert_eq!(breaker.suspended(), 0);
+    }
+}
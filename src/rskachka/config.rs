@@ -0,0 +1,60 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::Deserialize;
+
+/// Settings loaded from the `--config` TOML file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Headers sent with every request
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// Rules applied to a host and its subdomains
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>,
+
+    /// Where to fetch `s3://` URLs from, overriding the AWS environment
+    #[serde(default)]
+    pub s3: S3Config,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub host: String,
+
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// Proxy URL for the host, or "direct"
+    pub proxy: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file, or returns the defaults if no path is given.
+    pub fn load(path: Option<&Path>) -> std::io::Result<Self> {
+        match path {
+            Some(path) => {
+                let content = fs::read_to_string(path)?;
+                toml::from_str(&content).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Error parsing config {}: {}", path.display(), e),
+                    )
+                })
+            }
+            None => Ok(Config::default()),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rskachka::item::Item;
+
+/// At most this many processed images are remembered, forgetting the oldest.
+const MAX_DONE: usize = 1 << 20;
+
+enum State {
+    /// Being processed, with the duplicates to hand over when done
+    Pending(Vec<Item>),
+    /// Processed, saved at the path if it succeeded
+    Done(Option<Box<Path>>),
+}
+
+#[derive(Default)]
+struct Registry {
+    states: HashMap<[u8; 16], State>,
+    /// The processed images in the order they got done, to forget the oldest
+    done: VecDeque<[u8; 16]>,
+}
+
+impl Registry {
+    fn claim(&mut self, key: [u8; 16]) -> Option<&mut State> {
+        match self.states.entry(key) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                entry.insert(State::Pending(Vec::new()));
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self, key: [u8; 16], path: Option<PathBuf>, capacity: usize) -> Vec<Item> {
+        let Some(state) = self.states.get_mut(&key) else {
+            return Vec::new();
+        };
+        let duplicates = match mem::replace(state, State::Done(path.map(Into::into))) {
+            State::Pending(duplicates) => duplicates,
+            State::Done(_) => Vec::new(),
+        };
+        self.done.push_back(key);
+        while self.done.len() > capacity {
+            if let Some(oldest) = self.done.pop_front() {
+                self.states.remove(&oldest);
+            }
+        }
+        duplicates
+    }
+}
+
+/// The result of claiming an image for an item.
+pub enum Claim {
+    /// The image is ours to process
+    First(ClaimGuard, Item),
+    /// The image is processed already, saved at the path if it succeeded
+    Done(Item, Option<PathBuf>),
+    /// The image is being processed, which will take care of the item too
+    Attached,
+}
+
+/// Marks the image as processed, handing over the items attached to it meanwhile.
+pub struct ClaimGuard {
+    key: [u8; 16],
+    registry: Arc<Mutex<Registry>>,
+    capacity: usize,
+    finished: bool,
+}
+
+impl ClaimGuard {
+    /// Marks the image as processed, returning the duplicates to finish with the path.
+    pub fn finish(mut self, path: Option<PathBuf>) -> Vec<Item> {
+        self.finished = true;
+        self.registry
+            .lock()
+            .unwrap()
+            .finish(self.key, path, self.capacity)
+    }
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.registry
+                .lock()
+                .unwrap()
+                .finish(self.key, None, self.capacity);
+        }
+    }
+}
+
+/// Registry of the images processed in this run, so each is fetched once.
+/// Only a bounded number of the processed ones is remembered, the images
+/// being processed are kept until done.
+pub struct InFlight {
+    registry: Arc<Mutex<Registry>>,
+    capacity: usize,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight::with_capacity(MAX_DONE)
+    }
+}
+
+impl InFlight {
+    /// Remembers up to `capacity` processed images.
+    pub fn with_capacity(capacity: usize) -> Self {
+        InFlight {
+            registry: Arc::default(),
+            capacity,
+        }
+    }
+
+    /// Forgets the images which failed, so they can be claimed again.
+    pub fn forget_failed(&self) {
+        let registry = &mut *self.registry.lock().unwrap();
+        registry
+            .states
+            .retain(|_, state| !matches!(state, State::Done(None)));
+        let states = &registry.states;
+        registry
+            .done
+            .retain(|key| matches!(states.get(key), Some(State::Done(_))));
+    }
+
+    fn guard(&self, key: [u8; 16]) -> ClaimGuard {
+        ClaimGuard {
+            key,
+            registry: Arc::clone(&self.registry),
+            capacity: self.capacity,
+            finished: false,
+        }
+    }
+
+    /// Claims the image for the item, never waiting for another record to process it.
+    pub fn claim(&self, key: &str, item: Item) -> Claim {
+        // Hashed, as the registry lives for the whole run
+        let key = md5::compute(key).0;
+        match self.registry.lock().unwrap().claim(key) {
+            Some(State::Pending(duplicates)) => {
+                duplicates.push(item);
+                Claim::Attached
+            }
+            Some(State::Done(path)) => Claim::Done(item, path.as_deref().map(Path::to_path_buf)),
+            None => Claim::First(self.guard(key), item),
+        }
+    }
+
+    /// Claims the file unless claimed in this run already, with no item to attach.
+    pub fn claim_once(&self, key: &str) -> Option<ClaimGuard> {
+        let key = md5::compute(key).0;
+        match self.registry.lock().unwrap().claim(key) {
+            Some(_) => None,
+            None => Some(self.guard(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(url: &str) -> Item {
+        Item {
+            id: "1".to_string(),
+            url: url.to_string(),
+            fallbacks: Vec::new(),
+            position: None,
+            path: PathBuf::from(url),
+            crop: None,
+            original: None,
+        }
+    }
+
+    fn first(claim: Claim) -> ClaimGuard {
+        match claim {
+            Claim::First(guard, _) => guard,
+            _ => panic!("expected the first claim"),
+        }
+    }
+
+    #[test]
+    fn hands_over_duplicates() {
+        let in_flight = InFlight::default();
+        let guard = first(in_flight.claim("a", item("a")));
+        assert!(matches!(in_flight.claim("a", item("a")), Claim::Attached));
+        let duplicates = guard.finish(Some(PathBuf::from("saved")));
+        assert_eq!(duplicates.len(), 1);
+        assert!(matches!(
+            in_flight.claim("a", item("a")),
+            Claim::Done(_, Some(path)) if path == Path::new("saved")
+        ));
+    }
+
+    #[test]
+    fn forgets_failed() {
+        let in_flight = InFlight::default();
+        drop(first(in_flight.claim("a", item("a"))));
+        assert!(matches!(
+            in_flight.claim("a", item("a")),
+            Claim::Done(_, None)
+        ));
+        in_flight.forget_failed();
+        first(in_flight.claim("a", item("a"))).finish(None);
+    }
+
+    #[test]
+    fn reclaims_after_eviction() {
+        let in_flight = InFlight::with_capacity(1);
+        first(in_flight.claim("a", item("a"))).finish(Some(PathBuf::from("a")));
+        let pending = first(in_flight.claim("b", item("b")));
+        assert!(matches!(in_flight.claim("a", item("a")), Claim::Done(..)));
+
+        // Done with b evicts a, which gets processed again
+        pending.finish(Some(PathBuf::from("b")));
+        first(in_flight.claim("a", item("a"))).finish(Some(PathBuf::from("a")));
+        assert!(in_flight.claim_once("b").is_some());
+
+        // Images being processed are never forgotten
+        let pending = first(in_flight.claim("c", item("c")));
+        first(in_flight.claim("d", item("d"))).finish(None);
+        assert!(matches!(in_flight.claim("c", item("c")), Claim::Attached));
+        assert_eq!(pending.finish(None).len(), 1);
+    }
+}
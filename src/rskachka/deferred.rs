@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+
+use rskachka::item::Item;
+
+use crate::{group::Group, worker::ProcessError};
+
+/// Records which failed for reasons that may pass, to be retried after the main pass,
+/// along with their last error.
+#[derive(Default)]
+pub struct Deferred {
+    items: Mutex<Vec<(Item, ProcessError)>>,
+}
+
+impl Deferred {
+    pub fn push(&self, item: Item, err: ProcessError) {
+        self.items.lock().unwrap().push((item, err));
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the records out along with their last error.
+    pub fn take(&self) -> Vec<(Item, ProcessError)> {
+        std::mem::take(&mut *self.items.lock().unwrap())
+    }
+
+    /// Takes the records out, grouped again by their URLs.
+    pub fn take_groups(&self) -> Vec<Group> {
+        let mut groups: Vec<Group> = Vec::new();
+        for (item, _) in self.take() {
+            match groups.last_mut() {
+                Some(group) if group.accepts(&item) => group.items.push(item),
+                _ => groups.push(Group::new(item)),
+            }
+        }
+        groups
+    }
+}
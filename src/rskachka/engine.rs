@@ -0,0 +1,199 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crossbeam::channel::{bounded, unbounded, Sender};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use log::info;
+use tokio::{runtime::Runtime, sync::mpsc};
+
+use crate::{
+    args::Args,
+    fetcher::AsyncFetcher,
+    hosts::{HostPermit, HostScheduler, Work},
+    queues::Queues,
+    saving::SavingSemaphore,
+    shared::Shared,
+    worker::{Download, Finished, Job, Report, Worker},
+};
+
+/// Keeps thousands of downloads in flight on a few threads, handing the
+/// bodies to a fixed pool of threads which decode and save the images.
+pub struct AsyncEngine {
+    runtime: Runtime,
+    fetcher: AsyncFetcher,
+    in_flight: usize,
+    image_workers: usize,
+    queues: Arc<Queues>,
+}
+
+impl AsyncEngine {
+    pub fn new(args: &Args, shared: &Shared, in_flight: usize) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("fetcher")
+            .enable_all()
+            .build()?;
+        let fetcher = {
+            let _entered = runtime.enter();
+            AsyncFetcher::new(args, shared)?
+        };
+        Ok(AsyncEngine {
+            runtime,
+            fetcher,
+            in_flight,
+            image_workers: args.image_workers(),
+            queues: Arc::clone(&shared.queues),
+        })
+    }
+
+    /// Processes the scheduled groups, reporting the outcome of every item.
+    pub fn run(
+        &self,
+        args: &Args,
+        scheduler: &HostScheduler,
+        shared: &Shared,
+        stopped: &Arc<AtomicBool>,
+        saving: &Arc<SavingSemaphore>,
+        report: Report,
+    ) {
+        let worker = Worker::new(args, shared);
+        let queues = &self.queues;
+        let (wanted_tx, wanted_rx) = bounded::<()>(1);
+        let (work_tx, work_rx) = mpsc::channel(1);
+        let (images_tx, images_rx) = unbounded::<(Job, Download)>();
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+        thread::scope(|s| {
+            // Wait for the scheduler on a thread of its own, as it blocks, and
+            // only when asked to, so no permit waits for room in the dispatcher
+            thread::Builder::new()
+                .name("feeder".to_string())
+                .spawn_scoped(s, move || {
+                    for () in wanted_rx {
+                        let Some(next) = scheduler.next() else {
+                            break;
+                        };
+                        if work_tx.blocking_send(next).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+
+            for i in 0..self.image_workers {
+                let (images_rx, done_tx, worker) = (images_rx.clone(), done_tx.clone(), &worker);
+                thread::Builder::new()
+                    .name(format!("image{}", i))
+                    .stack_size(4 * 1024 * 1024)
+                    .spawn_scoped(s, move || {
+                        for (job, download) in images_rx {
+                            queues.image.pop();
+                            // Send the jobs whose image was unusable back for the next URL
+                            match worker.finish(job, download, stopped, saving) {
+                                Finished::Done(results) => {
+                                    for (item, result) in results {
+                                        report(item, result);
+                                    }
+                                    scheduler.complete();
+                                }
+                                Finished::Refetch(job) => {
+                                    queues.download.push();
+                                    scheduler.requeue(job);
+                                }
+                            }
+                            done_tx.send(()).ok();
+                        }
+                    })
+                    .unwrap();
+            }
+            drop((images_rx, done_tx));
+
+            self.runtime.block_on(self.dispatch(
+                &worker, scheduler, wanted_tx, work_rx, images_tx, done_rx, stopped,
+            ));
+        });
+    }
+
+    /// Starts downloads while under the in-flight limit, counting the bodies
+    /// waiting for the image workers too, so they can't pile up in memory.
+    /// The work is asked from the scheduler only when there's room for it.
+    #[allow(clippy::too_many_arguments)]
+    async fn dispatch<'s>(
+        &self,
+        worker: &Worker,
+        scheduler: &'s HostScheduler,
+        wanted: Sender<()>,
+        mut work: mpsc::Receiver<(Work, HostPermit<'s>)>,
+        images: Sender<(Job, Download)>,
+        mut done: mpsc::UnboundedReceiver<()>,
+        stopped: &Arc<AtomicBool>,
+    ) {
+        let mut downloads = FuturesUnordered::new();
+        let mut imaging = 0;
+        let mut asked = false;
+        loop {
+            if !asked && downloads.len() + imaging < self.in_flight {
+                wanted.send(()).unwrap();
+                asked = true;
+            }
+            tokio::select! {
+                next = work.recv(), if asked => {
+                    asked = false;
+                    let Some((work, permit)) = next else {
+                        break;
+                    };
+                    self.queues.download.pop();
+                    let job = match work {
+                        Work::Group(group) => worker.claim(group),
+                        Work::Job(job) => job,
+                    };
+                    downloads.push(self.download(worker, job, permit, stopped));
+                }
+                Some(()) = done.recv(), if imaging > 0 => {
+                    imaging -= 1;
+                }
+                Some((job, download)) = downloads.next(), if !downloads.is_empty() => {
+                    if let Download::Elsewhere = download {
+                        self.queues.download.push();
+                        scheduler.requeue(job);
+                        continue;
+                    }
+                    imaging += 1;
+                    self.queues.image.push();
+                    images.send((job, download)).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Fetches the image of the job, trying the URLs of the host in order
+    /// until one succeeds, holding the host permit meanwhile.
+    async fn download(
+        &self,
+        worker: &Worker,
+        mut job: Job,
+        _permit: HostPermit<'_>,
+        stopped: &Arc<AtomicBool>,
+    ) -> (Job, Download) {
+        loop {
+            let Some(url) = job.url().map(str::to_string) else {
+                return (job, Download::Nothing);
+            };
+            if stopped.load(Ordering::Relaxed) {
+                info!("Shutting down...");
+                return (job, Download::Stopped);
+            }
+            let known = worker.known_validators(&job, &url);
+            let fetched = self
+                .fetcher
+                .fetch_if_changed(&url, &known, job.deadline(), stopped)
+                .await;
+            if let Some(download) = worker.downloaded(&mut job, url, fetched) {
+                return (job, download);
+            }
+        }
+    }
+}
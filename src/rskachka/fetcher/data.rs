@@ -0,0 +1,52 @@
+use std::{sync::atomic::AtomicBool, time::Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use percent_encoding::percent_decode_str;
+
+use super::{FetchError, Fetcher};
+use crate::units::ByteSize;
+
+/// Decodes the payload inlined into `data` URLs.
+pub struct DataFetcher {
+    max_bytes: ByteSize,
+}
+
+impl DataFetcher {
+    pub fn new(max_bytes: ByteSize) -> Self {
+        DataFetcher { max_bytes }
+    }
+}
+
+impl Fetcher for DataFetcher {
+    fn fetch(
+        &self,
+        url: &str,
+        _deadline: Option<Instant>,
+        _stopped: &AtomicBool,
+    ) -> Result<Vec<u8>, FetchError> {
+        let (header, payload) = url
+            .split_once(':')
+            .and_then(|(_, rest)| rest.split_once(','))
+            .ok_or_else(|| FetchError::InvalidData("no comma after the media type".to_string()))?;
+        let payload = percent_decode_str(payload).collect::<Vec<u8>>();
+        let bytes = if header.to_ascii_lowercase().ends_with(";base64") {
+            // Line breaks and spaces are allowed inside the encoded payload
+            let payload = payload
+                .into_iter()
+                .filter(|byte| !byte.is_ascii_whitespace())
+                .collect::<Vec<u8>>();
+            STANDARD
+                .decode(payload)
+                .map_err(|e| FetchError::InvalidData(e.to_string()))?
+        } else {
+            payload
+        };
+        if bytes.len() as u64 > self.max_bytes.0 {
+            return Err(FetchError::TooLarge(
+                format!("data:{}", header),
+                self.max_bytes,
+            ));
+        }
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,37 @@
+use std::{fs, sync::atomic::AtomicBool, time::Instant};
+
+use url::Url;
+
+use super::{FetchError, Fetcher};
+use crate::units::ByteSize;
+
+/// Reads `file` URLs from the local filesystem.
+pub struct FileFetcher {
+    max_bytes: ByteSize,
+}
+
+impl FileFetcher {
+    pub fn new(max_bytes: ByteSize) -> Self {
+        FileFetcher { max_bytes }
+    }
+}
+
+impl Fetcher for FileFetcher {
+    fn fetch(
+        &self,
+        url: &str,
+        _deadline: Option<Instant>,
+        _stopped: &AtomicBool,
+    ) -> Result<Vec<u8>, FetchError> {
+        let path = Url::parse(url)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| FetchError::InvalidUrl(url.to_string()))?;
+        let display = path.display().to_string();
+        let metadata = fs::metadata(&path).map_err(|e| FetchError::File(display.clone(), e))?;
+        if metadata.len() > self.max_bytes.0 {
+            return Err(FetchError::TooLarge(display, self.max_bytes));
+        }
+        fs::read(&path).map_err(|e| FetchError::File(display, e))
+    }
+}
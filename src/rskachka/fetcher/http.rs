@@ -0,0 +1,191 @@
+use std::{
+    io::{ErrorKind, Read},
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::{
+    is_past,
+    policy::{Attempt, Attempts, ResponseLimits},
+    FetchError, Fetched, Fetcher,
+};
+use crate::{
+    abort::sleep_unless_stopped,
+    args::Args,
+    bandwidth::{BandwidthLimiter, ThrottledReader},
+    breaker::CircuitBreaker,
+    headers::HeaderRules,
+    proxy::{Agents, ProxyRoutes},
+    rate::RateLimiter,
+    refresh::Validators,
+    retry::RetryPolicy,
+    robots::RobotsCache,
+    shared::Shared,
+};
+
+/// Fails reads once the deadline has passed, so a server trickling
+/// the body can't hold a worker for longer than that.
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Option<Instant>,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if is_past(self.deadline) {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "deadline exceeded",
+            ));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Downloads `http` and `https` URLs with retries, rate limits and robots.txt.
+pub struct HttpFetcher {
+    agents: Arc<Agents>,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+    bandwidth: Arc<BandwidthLimiter>,
+    breaker: Arc<CircuitBreaker>,
+    robots: Option<Arc<RobotsCache>>,
+    headers: Arc<HeaderRules>,
+    routes: Arc<ProxyRoutes>,
+    limits: ResponseLimits,
+}
+
+impl HttpFetcher {
+    pub fn new(args: &Args, shared: &Shared) -> Self {
+        HttpFetcher {
+            agents: Arc::clone(&shared.agents),
+            retry: RetryPolicy::from(args),
+            limiter: Arc::clone(&shared.limiter),
+            bandwidth: Arc::clone(&shared.bandwidth),
+            breaker: Arc::clone(&shared.breaker),
+            robots: shared.robots.clone(),
+            headers: Arc::clone(&shared.headers),
+            routes: Arc::clone(&shared.routes),
+            limits: ResponseLimits::new(args),
+        }
+    }
+
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    /// Prepares a GET request routed and with the headers configured for the host.
+    pub fn request(&self, url: &str, host: &str) -> ureq::Request {
+        let scheme = url.split_once(':').map_or("", |(scheme, _)| scheme);
+        let agent = self.agents.get(self.routes.route(scheme, host));
+        self.headers
+            .for_host(host)
+            .into_iter()
+            .fold(agent.get(url), |request, header| {
+                request.set(&header.name, &header.value)
+            })
+    }
+
+    /// Downloads the URL, retrying transient failures until the optional deadline.
+    /// Each attempt's request is passed through `prepare`, e.g. to sign it.
+    pub fn fetch_with(
+        &self,
+        url: &str,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+        prepare: &dyn Fn(ureq::Request) -> ureq::Request,
+    ) -> Result<Fetched, FetchError> {
+        let mut attempts = Attempts::new(url, deadline, &self.retry, &self.breaker);
+        loop {
+            let waited = self.limiter.acquire(attempts.host(), stopped);
+            if waited > Duration::ZERO {
+                debug!("Throttled {} for {:?}", url, waited);
+            }
+            attempts.start()?;
+            let result = self.fetch_once(url, attempts.host(), deadline, stopped, prepare);
+            match attempts.finish(result) {
+                Attempt::Done(result) => return result,
+                Attempt::Retry(err, delay) => {
+                    if !sleep_unless_stopped(delay, stopped) {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn fetch_once(
+        &self,
+        url: &str,
+        host: &str,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+        prepare: &dyn Fn(ureq::Request) -> ureq::Request,
+    ) -> Result<Fetched, FetchError> {
+        let mut request = prepare(self.request(url, host));
+        // Keep any single blocking read from running past the deadline
+        if let Some(deadline) = deadline {
+            request = request.timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        let response = request
+            .call()
+            .map_err(|e| FetchError::from_ureq(e, url, deadline))?;
+        if response.status() == 304 {
+            return Ok(Fetched::Unchanged);
+        }
+        let validators = Validators::from_response(&response);
+
+        let content_length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        self.limits
+            .check_headers(url, response.header("Content-Type"), content_length)?;
+
+        // Read one byte over the limit to tell if the body exceeds it
+        let mut reader = DeadlineReader {
+            inner: ThrottledReader {
+                inner: response.into_reader(),
+                limiter: &self.bandwidth,
+                host,
+                stopped,
+            },
+            deadline,
+        }
+        .take(self.limits.max_bytes.0.saturating_add(1));
+        let mut buffer = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+        reader
+            .read_to_end(&mut buffer)
+            .map_err(|e| FetchError::from_io(e, url, deadline))?;
+        self.limits.check_body(url, buffer.len())?;
+        Ok(Fetched::Modified(buffer, validators))
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn fetch(
+        &self,
+        url: &str,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Vec<u8>, FetchError> {
+        self.fetch_if_changed(url, &Validators::default(), deadline, stopped)
+            .map(Fetched::into_bytes)
+    }
+
+    fn fetch_if_changed(
+        &self,
+        url: &str,
+        known: &Validators,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Fetched, FetchError> {
+        if let Some(robots) = &self.robots {
+            if !robots.is_allowed(url, self)? {
+                return Err(FetchError::DisallowedByRobots);
+            }
+        }
+        self.fetch_with(url, deadline, stopped, &|request| known.apply(request))
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::HashMap,
+    error::Error as _,
+    io::ErrorKind,
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+
+use thiserror::Error;
+
+use crate::{args::Args, refresh::Validators, shared::Shared, units::ByteSize};
+
+mod data;
+mod file;
+mod http;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod policy;
+mod s3;
+
+pub use http::HttpFetcher;
+#[cfg(feature = "async")]
+pub use nonblocking::AsyncFetcher;
+pub use s3::S3Settings;
+
+#[derive(Error, Debug)]
+pub enum FetchError {
+    #[error("IO error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("Network request error: {0}")]
+    Network(Box<ureq::Error>),
+
+    #[cfg(feature = "async")]
+    #[error("Network request error: {0}")]
+    Request(reqwest::Error),
+
+    #[cfg(feature = "async")]
+    #[error("Network request error: {0}: status code {1}")]
+    Status(String, u16, Option<Duration>),
+
+    #[error("Connect timeout: {0}")]
+    ConnectTimeout(String),
+
+    #[error("Read timeout: {0}")]
+    ReadTimeout(String),
+
+    #[error("Deadline exceeded")]
+    Deadline,
+
+    #[error("Disallowed by robots.txt")]
+    DisallowedByRobots,
+
+    #[error("Can't get robots.txt of {0}: {1}")]
+    RobotsUnavailable(String, String),
+
+    #[error("Response is larger than {1}: {0}")]
+    TooLarge(String, ByteSize),
+
+    #[error("Unexpected content type {1}: {0}")]
+    ContentType(String, String),
+
+    #[error("Can't read {0}: {1}")]
+    File(String, std::io::Error),
+
+    #[error("Invalid data URL: {0}")]
+    InvalidData(String),
+
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("Unsupported URL scheme: {0}")]
+    UnsupportedScheme(String),
+
+    #[error("Host suspended after repeated failures: {0}")]
+    HostSuspended(String),
+}
+
+impl FetchError {
+    /// Whether the request may succeed if repeated later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::IO(err) => is_transient_io(err.kind()),
+            FetchError::Network(err) => match err.as_ref() {
+                ureq::Error::Status(code, _) => is_retryable_status(*code),
+                ureq::Error::Transport(transport) => matches!(
+                    transport.kind(),
+                    ureq::ErrorKind::ConnectionFailed
+                        | ureq::ErrorKind::Io
+                        | ureq::ErrorKind::BadStatus
+                        | ureq::ErrorKind::ProxyConnect
+                ),
+            },
+            #[cfg(feature = "async")]
+            FetchError::Request(err) => err.is_connect() || err.is_request() || err.is_body(),
+            #[cfg(feature = "async")]
+            FetchError::Status(_, code, _) => is_retryable_status(*code),
+            FetchError::ConnectTimeout(_)
+            | FetchError::ReadTimeout(_)
+            | FetchError::RobotsUnavailable(..) => true,
+            FetchError::Deadline
+            | FetchError::DisallowedByRobots
+            | FetchError::TooLarge(..)
+            | FetchError::ContentType(..)
+            | FetchError::File(..)
+            | FetchError::InvalidData(_)
+            | FetchError::InvalidUrl(_)
+            | FetchError::UnsupportedScheme(_)
+            | FetchError::HostSuspended(_) => false,
+        }
+    }
+
+    /// Whether the host couldn't be reached or stopped sending, rather than answering.
+    /// Running out of the time of the item says nothing about the host.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            FetchError::IO(err) => is_transient_io(err.kind()),
+            FetchError::Network(err) => matches!(
+                err.as_ref(),
+                ureq::Error::Transport(transport) if matches!(
+                    transport.kind(),
+                    ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+                )
+            ),
+            #[cfg(feature = "async")]
+            FetchError::Request(err) => err.is_connect(),
+            FetchError::ConnectTimeout(_) | FetchError::ReadTimeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Tells timeouts apart from other IO errors.
+    fn from_io(err: std::io::Error, url: &str, deadline: Option<Instant>) -> Self {
+        match err.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock if is_past(deadline) => {
+                FetchError::Deadline
+            }
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => FetchError::ReadTimeout(url.to_string()),
+            _ => FetchError::IO(err),
+        }
+    }
+
+    /// Tells connect and read timeouts apart from other request errors.
+    fn from_ureq(err: ureq::Error, url: &str, deadline: Option<Instant>) -> Self {
+        if let ureq::Error::Transport(transport) = &err {
+            let timed_out = transport
+                .source()
+                .and_then(|source| source.downcast_ref::<std::io::Error>())
+                .is_some_and(|e| matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock));
+            if timed_out {
+                return if is_past(deadline) {
+                    FetchError::Deadline
+                } else if transport.kind() == ureq::ErrorKind::ConnectionFailed {
+                    FetchError::ConnectTimeout(url.to_string())
+                } else {
+                    FetchError::ReadTimeout(url.to_string())
+                };
+            }
+        }
+        FetchError::Network(Box::new(err))
+    }
+
+    /// Tells connect and read timeouts apart from other async request errors.
+    #[cfg(feature = "async")]
+    fn from_reqwest(err: reqwest::Error, url: &str, deadline: Option<Instant>) -> Self {
+        match err.is_timeout() {
+            true if is_past(deadline) => FetchError::Deadline,
+            true if err.is_connect() => FetchError::ConnectTimeout(url.to_string()),
+            true => FetchError::ReadTimeout(url.to_string()),
+            false => FetchError::Request(err),
+        }
+    }
+
+    /// The delay requested by the server with a `Retry-After` header, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::Network(err) => match err.as_ref() {
+                ureq::Error::Status(_, response) => {
+                    parse_retry_after(response.header("Retry-After")?)
+                }
+                _ => None,
+            },
+            #[cfg(feature = "async")]
+            FetchError::Status(_, _, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Whether the optional deadline has passed.
+pub fn is_past(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Whether the status tells the server may answer differently later.
+fn is_retryable_status(code: u16) -> bool {
+    matches!(code, 408 | 429) || (500..=599).contains(&code) && code != 501
+}
+
+fn is_transient_io(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value).ok().map(|date| {
+            date.duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+        }),
+    }
+}
+
+/// What a conditional download returned.
+pub enum Fetched {
+    Modified(Vec<u8>, Validators),
+    Unchanged,
+}
+
+impl Fetched {
+    /// The body, which is empty if the resource hasn't changed.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Fetched::Modified(bytes, _) => bytes,
+            Fetched::Unchanged => Vec::new(),
+        }
+    }
+}
+
+/// A way to get the bytes behind a URL.
+pub trait Fetcher: Send + Sync {
+    fn fetch(
+        &self,
+        url: &str,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Vec<u8>, FetchError>;
+
+    /// Downloads the URL unless it still matches the known validators.
+    /// Fetchers which can't tell download it anyway.
+    fn fetch_if_changed(
+        &self,
+        url: &str,
+        _known: &Validators,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Fetched, FetchError> {
+        self.fetch(url, deadline, stopped)
+            .map(|bytes| Fetched::Modified(bytes, Validators::default()))
+    }
+}
+
+/// Dispatches URLs to the fetchers registered for their schemes.
+pub struct Fetchers {
+    schemes: HashMap<String, Arc<dyn Fetcher>>,
+}
+
+impl Fetchers {
+    /// Registers the built-in fetchers for `http`, `https`, `s3`, `file` and `data` URLs.
+    pub fn new(args: &Args, shared: &Shared) -> Self {
+        let http = Arc::new(HttpFetcher::new(args, shared));
+        let mut fetchers = Fetchers {
+            schemes: HashMap::new(),
+        };
+        fetchers.register("http", http.clone());
+        fetchers.register("https", http.clone());
+        fetchers.register(
+            "s3",
+            Arc::new(s3::S3Fetcher::new(http, Arc::clone(&shared.s3))),
+        );
+        fetchers.register("file", Arc::new(file::FileFetcher::new(args.max_bytes)));
+        fetchers.register("data", Arc::new(data::DataFetcher::new(args.max_bytes)));
+        fetchers
+    }
+
+    /// Makes the fetcher handle the scheme, replacing the previous one.
+    pub fn register(&mut self, scheme: &str, fetcher: Arc<dyn Fetcher>) {
+        self.schemes.insert(scheme.to_ascii_lowercase(), fetcher);
+    }
+}
+
+impl Fetchers {
+    fn lookup(&self, url: &str) -> Result<&dyn Fetcher, FetchError> {
+        let scheme = url.split_once(':').map_or("", |(scheme, _)| scheme);
+        self.schemes
+            .get(&scheme.to_ascii_lowercase())
+            .map(|fetcher| fetcher.as_ref())
+            .ok_or_else(|| FetchError::UnsupportedScheme(scheme.to_string()))
+    }
+}
+
+impl Fetcher for Fetchers {
+    fn fetch(
+        &self,
+        url: &str,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Vec<u8>, FetchError> {
+        self.lookup(url)?.fetch(url, deadline, stopped)
+    }
+
+    fn fetch_if_changed(
+        &self,
+        url: &str,
+        known: &Validators,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Fetched, FetchError> {
+        self.lookup(url)?
+            .fetch_if_changed(url, known, deadline, stopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_is_not_unreachable() {
+        let timeout = std::io::Error::from(ErrorKind::TimedOut);
+        assert!(FetchError::from_io(timeout, "http://a.com/", None).is_unreachable());
+        assert!(FetchError::ConnectTimeout("http://a.com/".to_string()).is_unreachable());
+        assert!(!FetchError::Deadline.is_unreachable());
+        let past = Some(Instant::now());
+        let timeout = std::io::Error::from(ErrorKind::TimedOut);
+        assert!(!FetchError::from_io(timeout, "http://a.com/", past).is_unreachable());
+    }
+
+    /// Only the fetchers which don't need the network or the shared state.
+    fn offline() -> Fetchers {
+        let mut fetchers = Fetchers {
+            schemes: HashMap::new(),
+        };
+        let max_bytes = ByteSize(1024);
+        fetchers.register("file", Arc::new(file::FileFetcher::new(max_bytes)));
+        fetchers.register("data", Arc::new(data::DataFetcher::new(max_bytes)));
+        fetchers
+    }
+
+    #[test]
+    fn dispatches_by_scheme() {
+        let fetchers = offline();
+        let stopped = AtomicBool::new(false);
+        let path = std::env::temp_dir().join(format!("rskachka-fetchers-{}", std::process::id()));
+        std::fs::write(&path, b"file").unwrap();
+        let url = url::Url::from_file_path(&path).unwrap();
+        let fetched = fetchers.fetch(url.as_str(), None, &stopped);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(fetched.unwrap(), b"file");
+        for url in ["data:,data", "DATA:text/plain;base64,ZGF0YQ=="] {
+            assert_eq!(
+                fetchers.fetch(url, None, &stopped).unwrap(),
+                b"data",
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_schemes() {
+        let fetchers = offline();
+        let stopped = AtomicBool::new(false);
+        for (url, scheme) in [("ftp://a.com/1.jpg", "ftp"), ("n/a", "")] {
+            match fetchers.fetch(url, None, &stopped) {
+                Err(FetchError::UnsupportedScheme(found)) => assert_eq!(found, scheme),
+                other => panic!("{}: {:?}", url, other.map(|_| ())),
+            }
+        }
+    }
+}
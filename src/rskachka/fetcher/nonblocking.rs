@@ -0,0 +1,210 @@
+use std::{
+    future::Future,
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::{
+    parse_retry_after,
+    policy::{Attempt, Attempts, ResponseLimits},
+    FetchError, Fetched, Fetcher, Fetchers,
+};
+use crate::{
+    abort::sleep_unless_stopped_async,
+    args::Args,
+    bandwidth::BandwidthLimiter,
+    breaker::CircuitBreaker,
+    headers::HeaderRules,
+    pool::{self, PoolStats},
+    proxy::{Clients, ProxyRoutes},
+    rate::RateLimiter,
+    refresh::Validators,
+    retry::RetryPolicy,
+    robots::RobotsCache,
+    shared::Shared,
+};
+
+/// Fails with `FetchError::Deadline` unless the future completes before the optional deadline.
+async fn within<T>(
+    deadline: Option<Instant>,
+    future: impl Future<Output = T>,
+) -> Result<T, FetchError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future)
+            .await
+            .map_err(|_| FetchError::Deadline),
+        None => Ok(future.await),
+    }
+}
+
+/// Downloads `http` and `https` URLs without holding a thread per request,
+/// with HTTP/2 multiplexing where the servers support it. The rate and
+/// bandwidth limits, the breaker and the retries are shared with the blocking
+/// fetchers, which still handle the other schemes and robots.txt.
+pub struct AsyncFetcher {
+    clients: Clients,
+    pool: Arc<PoolStats>,
+    blocking: Arc<Fetchers>,
+    robots: Option<(Arc<RobotsCache>, Arc<super::HttpFetcher>)>,
+    retry: RetryPolicy,
+    limiter: Arc<RateLimiter>,
+    bandwidth: Arc<BandwidthLimiter>,
+    breaker: Arc<CircuitBreaker>,
+    headers: Arc<HeaderRules>,
+    routes: Arc<ProxyRoutes>,
+    limits: ResponseLimits,
+}
+
+impl AsyncFetcher {
+    pub fn new(args: &Args, shared: &Shared) -> std::io::Result<Self> {
+        Ok(AsyncFetcher {
+            clients: Clients::new(&shared.routes, || pool::client_builder(args, &shared.pool))?,
+            pool: Arc::clone(&shared.pool),
+            blocking: Arc::new(Fetchers::new(args, shared)),
+            robots: shared.robots.as_ref().map(|robots| {
+                (
+                    Arc::clone(robots),
+                    Arc::new(super::HttpFetcher::new(args, shared)),
+                )
+            }),
+            retry: RetryPolicy::from(args),
+            limiter: Arc::clone(&shared.limiter),
+            bandwidth: Arc::clone(&shared.bandwidth),
+            breaker: Arc::clone(&shared.breaker),
+            headers: Arc::clone(&shared.headers),
+            routes: Arc::clone(&shared.routes),
+            limits: ResponseLimits::new(args),
+        })
+    }
+
+    /// Downloads the URL unless it still matches the known validators.
+    pub async fn fetch_if_changed(
+        &self,
+        url: &str,
+        known: &Validators,
+        deadline: Option<Instant>,
+        stopped: &Arc<AtomicBool>,
+    ) -> Result<Fetched, FetchError> {
+        let scheme = url.split_once(':').map_or("", |(scheme, _)| scheme);
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            let (blocking, url, known, stopped) = (
+                Arc::clone(&self.blocking),
+                url.to_string(),
+                known.clone(),
+                Arc::clone(stopped),
+            );
+            return tokio::task::spawn_blocking(move || {
+                blocking.fetch_if_changed(&url, &known, deadline, &stopped)
+            })
+            .await
+            .map_err(|e| FetchError::IO(std::io::Error::other(e)))?;
+        }
+        if let Some((robots, http)) = &self.robots {
+            let (robots, http, robots_url) =
+                (Arc::clone(robots), Arc::clone(http), url.to_string());
+            let allowed =
+                tokio::task::spawn_blocking(move || robots.is_allowed(&robots_url, &http))
+                    .await
+                    .map_err(|e| FetchError::IO(std::io::Error::other(e)))??;
+            if !allowed {
+                return Err(FetchError::DisallowedByRobots);
+            }
+        }
+        self.fetch_http(url, known, deadline, stopped).await
+    }
+
+    /// Downloads the URL, retrying transient failures until the optional deadline.
+    async fn fetch_http(
+        &self,
+        url: &str,
+        known: &Validators,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Fetched, FetchError> {
+        let mut attempts = Attempts::new(url, deadline, &self.retry, &self.breaker);
+        loop {
+            let delay = self.limiter.reserve(attempts.host());
+            if delay > Duration::ZERO {
+                debug!("Throttled {} for {:?}", url, delay);
+                let _held = self.limiter.hold();
+                sleep_unless_stopped_async(delay, stopped).await;
+            }
+            attempts.start()?;
+            let result = self
+                .fetch_once(url, attempts.host(), known, deadline, stopped)
+                .await;
+            match attempts.finish(result) {
+                Attempt::Done(result) => return result,
+                Attempt::Retry(err, delay) => {
+                    if !sleep_unless_stopped_async(delay, stopped).await {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn fetch_once(
+        &self,
+        url: &str,
+        host: &str,
+        known: &Validators,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+    ) -> Result<Fetched, FetchError> {
+        // Route the request and set the headers configured for the host
+        let scheme = url.split_once(':').map_or("", |(scheme, _)| scheme);
+        let client = self.clients.get(self.routes.route(scheme, host));
+        let request = self
+            .headers
+            .for_host(host)
+            .into_iter()
+            .fold(client.get(url), |request, header| {
+                request.header(&header.name, &header.value)
+            });
+        let request = known.conditions().fold(request, |request, (name, value)| {
+            request.header(name, value)
+        });
+        self.pool.count_request();
+        let mut response = within(deadline, request.send())
+            .await?
+            .map_err(|e| FetchError::from_reqwest(e, url, deadline))?;
+
+        let status = response.status().as_u16();
+        if status == 304 {
+            return Ok(Fetched::Unchanged);
+        }
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        if status >= 400 {
+            let retry_after = header("Retry-After").and_then(parse_retry_after);
+            return Err(FetchError::Status(url.to_string(), status, retry_after));
+        }
+        let validators = Validators::from_headers(header);
+
+        let content_length = response.content_length();
+        self.limits
+            .check_headers(url, header("Content-Type"), content_length)?;
+
+        // Read the body as it arrives, within the limits
+        let mut buffer = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+        while let Some(chunk) = within(deadline, response.chunk())
+            .await?
+            .map_err(|e| FetchError::from_reqwest(e, url, deadline))?
+        {
+            buffer.extend_from_slice(&chunk);
+            self.limits.check_body(url, buffer.len())?;
+            let delay = self.bandwidth.reserve(host, chunk.len());
+            if delay > Duration::ZERO {
+                sleep_unless_stopped_async(delay, stopped).await;
+            }
+        }
+        Ok(Fetched::Modified(buffer, validators))
+    }
+}
@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::{is_past, FetchError, Fetched};
+use crate::{
+    args::Args, breaker::CircuitBreaker, hosts::host_of, retry::RetryPolicy, units::ByteSize,
+};
+
+/// Whether the MIME type matches one of the `type/subtype`, `type/*` or `*/*` patterns.
+fn is_accepted(content_type: &str, patterns: &[String]) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let main = mime.split('/').next().unwrap_or_default();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some("*") => true,
+            Some(pattern_main) => pattern_main == main,
+            None => *pattern == mime,
+        })
+}
+
+/// What the responses of the HTTP fetchers have to be like to be downloaded.
+pub(super) struct ResponseLimits {
+    pub max_bytes: ByteSize,
+    content_types: Vec<String>,
+}
+
+impl ResponseLimits {
+    pub fn new(args: &Args) -> Self {
+        ResponseLimits {
+            max_bytes: args.max_bytes,
+            content_types: args
+                .content_types
+                .iter()
+                .map(|pattern| pattern.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Rejects what is surely not an image before downloading it.
+    pub fn check_headers(
+        &self,
+        url: &str,
+        content_type: Option<&str>,
+        content_length: Option<u64>,
+    ) -> Result<(), FetchError> {
+        if let Some(content_type) = content_type {
+            if !is_accepted(content_type, &self.content_types) {
+                return Err(FetchError::ContentType(
+                    url.to_string(),
+                    content_type.to_string(),
+                ));
+            }
+        }
+        if content_length.is_some_and(|length| length > self.max_bytes.0) {
+            return Err(FetchError::TooLarge(url.to_string(), self.max_bytes));
+        }
+        Ok(())
+    }
+
+    /// Fails once the body read so far is over the limit.
+    pub fn check_body(&self, url: &str, length: usize) -> Result<(), FetchError> {
+        if length as u64 > self.max_bytes.0 {
+            return Err(FetchError::TooLarge(url.to_string(), self.max_bytes));
+        }
+        Ok(())
+    }
+}
+
+/// What to do after an attempt.
+pub(super) enum Attempt {
+    Done(Result<Fetched, FetchError>),
+    /// Try again after the delay, failing with the error if stopped meanwhile
+    Retry(FetchError, Duration),
+}
+
+/// Takes a download through its attempts: whether each may start, and whether
+/// to retry after a failure, keeping the breaker informed.
+pub(super) struct Attempts<'a> {
+    url: &'a str,
+    host: String,
+    deadline: Option<Instant>,
+    retry: &'a RetryPolicy,
+    breaker: &'a CircuitBreaker,
+    attempt: u32,
+}
+
+impl<'a> Attempts<'a> {
+    pub fn new(
+        url: &'a str,
+        deadline: Option<Instant>,
+        retry: &'a RetryPolicy,
+        breaker: &'a CircuitBreaker,
+    ) -> Self {
+        Attempts {
+            url,
+            host: host_of(url),
+            deadline,
+            retry,
+            breaker,
+            attempt: 0,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Whether the next attempt may start, once the rate limit lets it.
+    pub fn start(&self) -> Result<(), FetchError> {
+        if is_past(self.deadline) {
+            return Err(FetchError::Deadline);
+        }
+        if !self.breaker.allow(&self.host) {
+            return Err(FetchError::HostSuspended(self.host.clone()));
+        }
+        Ok(())
+    }
+
+    /// Reports the outcome of the attempt to the breaker and decides whether to retry.
+    pub fn finish(&mut self, result: Result<Fetched, FetchError>) -> Attempt {
+        let reached = !matches!(&result, Err(err) if err.is_unreachable());
+        self.breaker.record(&self.host, reached);
+        let err = match result {
+            Err(err) => err,
+            result => return Attempt::Done(result),
+        };
+        let Some(delay) = self.retry.retry_delay(&err, self.attempt, self.deadline) else {
+            return Attempt::Done(Err(err));
+        };
+        self.attempt += 1;
+        debug!(
+            "Retrying {} in {:?} ({}/{}): {}",
+            self.url, delay, self.attempt, self.retry.retries, err
+        );
+        Attempt::Retry(err, delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ResponseLimits {
+        ResponseLimits {
+            max_bytes: ByteSize(100),
+            content_types: vec![
+                "image/*".to_string(),
+                "application/octet-stream".to_string(),
+            ],
+        }
+    }
+
+    #[test]
+    fn checks_content_type() {
+        let limits = limits();
+        let url = "http://a.com/1.jpg";
+        assert!(limits.check_headers(url, Some("image/jpeg"), None).is_ok());
+        assert!(limits
+            .check_headers(url, Some("Application/Octet-Stream; charset=x"), None)
+            .is_ok());
+        assert!(limits.check_headers(url, None, None).is_ok());
+        assert!(matches!(
+            limits.check_headers(url, Some("text/html"), None),
+            Err(FetchError::ContentType(..))
+        ));
+    }
+
+    #[test]
+    fn checks_size() {
+        let limits = limits();
+        let url = "http://a.com/1.jpg";
+        assert!(limits.check_headers(url, None, Some(100)).is_ok());
+        assert!(matches!(
+            limits.check_headers(url, None, Some(101)),
+            Err(FetchError::TooLarge(..))
+        ));
+        assert!(limits.check_body(url, 100).is_ok());
+        assert!(limits.check_body(url, 101).is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use rskachka::item::Item;
+
+/// At most this many records are fetched together, so none waits for too long.
+const MAX_GROUP: usize = 1024;
+
+/// Consecutive records sharing their URLs, fetched once and saved for each.
+pub struct Group {
+    pub items: Vec<Item>,
+}
+
+impl Group {
+    pub fn new(item: Item) -> Self {
+        Group { items: vec![item] }
+    }
+
+    /// The primary URL of the records.
+    pub fn url(&self) -> &str {
+        &self.items[0].url
+    }
+
+    /// Whether the item shares the URLs and there's room for it.
+    pub fn accepts(&self, item: &Item) -> bool {
+        self.items.len() < MAX_GROUP && item.urls().eq(self.items[0].urls())
+    }
+}
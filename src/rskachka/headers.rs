@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use crate::{args::Args, config::Config, hosts::host_matches};
+
+/// A `Name: value` request header.
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected NAME: VALUE, got {:?}", s))?;
+        Ok(Header::new(name.trim(), value.trim()))
+    }
+}
+
+impl Header {
+    fn new(name: &str, value: &str) -> Self {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Global and per-host request headers.
+#[derive(Default)]
+pub struct HeaderRules {
+    global: Vec<Header>,
+    hosts: Vec<(String, Vec<Header>)>,
+}
+
+fn upsert(headers: &mut Vec<Header>, header: Header) {
+    match headers
+        .iter_mut()
+        .find(|h| h.name.eq_ignore_ascii_case(&header.name))
+    {
+        Some(existing) => existing.value = header.value,
+        None => headers.push(header),
+    }
+}
+
+impl HeaderRules {
+    /// Combines the config file headers with the command line ones,
+    /// the latter taking precedence.
+    pub fn new(args: &Args, config: &Config) -> Self {
+        let mut global = Vec::new();
+        for (name, value) in &config.headers {
+            upsert(&mut global, Header::new(name, value));
+        }
+        for (name, value) in [
+            ("User-Agent", &args.user_agent),
+            ("Referer", &args.referer),
+            ("Accept", &args.accept),
+        ] {
+            if let Some(value) = value {
+                upsert(&mut global, Header::new(name, value));
+            }
+        }
+        for header in &args.header {
+            upsert(&mut global, header.clone());
+        }
+
+        let hosts = config
+            .hosts
+            .iter()
+            .filter(|rule| !rule.headers.is_empty())
+            .map(|rule| {
+                let headers = rule
+                    .headers
+                    .iter()
+                    .map(|(name, value)| Header::new(name, value))
+                    .collect();
+                (rule.host.to_lowercase(), headers)
+            })
+            .collect();
+
+        HeaderRules { global, hosts }
+    }
+
+    /// Returns the headers for the host, host rules overriding global ones.
+    pub fn for_host(&self, host: &str) -> Vec<Header> {
+        let mut headers = self.global.clone();
+        for (pattern, rule_headers) in &self.hosts {
+            if host_matches(host, pattern) {
+                for header in rule_headers {
+                    upsert(&mut headers, header.clone());
+                }
+            }
+        }
+        headers
+    }
+}
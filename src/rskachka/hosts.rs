@@ -0,0 +1,316 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError, TryRecvError};
+
+use crate::{group::Group, worker::Job};
+
+pub use rskachka::hosts::{host_matches, host_of};
+
+const MAX_PARKED: usize = 4096;
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Returns the registered domain of the host, falling back to the host itself.
+pub fn domain_of(host: &str) -> &str {
+    psl::domain_str(host).unwrap_or(host)
+}
+
+struct HostKeys {
+    host: String,
+    domain: String,
+}
+
+impl HostKeys {
+    fn of(url: &str) -> Self {
+        let host = host_of(url);
+        let domain = domain_of(&host).to_string();
+        HostKeys { host, domain }
+    }
+}
+
+/// What the scheduler hands out: a group read from the source, or a job
+/// which came back to be fetched from its next URL.
+pub enum Work {
+    Group(Group),
+    Job(Job),
+}
+
+#[derive(Default)]
+struct State {
+    hosts: HashMap<String, usize>,
+    domains: HashMap<String, usize>,
+    parked: VecDeque<(HostKeys, Work)>,
+    /// The work handed out which may still come back
+    outstanding: usize,
+    exhausted: bool,
+}
+
+/// Hands out groups of items to the workers, keeping the number of in-flight
+/// requests per host and per registered domain under the configured limits.
+///
+/// Items for saturated hosts are parked and picked up as soon as a slot
+/// frees up, so workers move on to other hosts instead of blocking.
+/// The jobs falling back to another URL come back through `requeue`, so
+/// they wait for a slot the same way, and every other job is reported
+/// with `complete`, so the workers keep going until none may come back.
+pub struct HostScheduler {
+    work_rx: Receiver<Group>,
+    host_limit: usize,
+    domain_limit: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// Holds an in-flight slot for a host until dropped.
+pub struct HostPermit<'a> {
+    scheduler: &'a HostScheduler,
+    keys: Option<HostKeys>,
+}
+
+impl HostScheduler {
+    /// Creates a scheduler, zero limits meaning no limit.
+    pub fn new(work_rx: Receiver<Group>, host_limit: usize, domain_limit: usize) -> Self {
+        HostScheduler {
+            work_rx,
+            host_limit,
+            domain_limit,
+            state: Mutex::new(State::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.host_limit > 0 || self.domain_limit > 0
+    }
+
+    fn has_room(&self, state: &State, keys: &HostKeys) -> bool {
+        let below = |counts: &HashMap<String, usize>, key: &str, limit: usize| {
+            limit == 0 || counts.get(key).copied().unwrap_or(0) < limit
+        };
+        below(&state.hosts, &keys.host, self.host_limit)
+            && below(&state.domains, &keys.domain, self.domain_limit)
+    }
+
+    fn hand_out(&self, state: &mut State, keys: HostKeys, work: Work) -> (Work, HostPermit<'_>) {
+        state.outstanding += 1;
+        if !self.is_limited() {
+            let permit = HostPermit {
+                scheduler: self,
+                keys: None,
+            };
+            return (work, permit);
+        }
+        *state.hosts.entry(keys.host.clone()).or_default() += 1;
+        *state.domains.entry(keys.domain.clone()).or_default() += 1;
+        let permit = HostPermit {
+            scheduler: self,
+            keys: Some(keys),
+        };
+        (work, permit)
+    }
+
+    fn release(&self, keys: &HostKeys) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        for (counts, key) in [
+            (&mut state.hosts, &keys.host),
+            (&mut state.domains, &keys.domain),
+        ] {
+            if let Some(count) = counts.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+        self.released.notify_all();
+    }
+
+    /// Takes back a job handed out, to hand it out again once its host has
+    /// a free slot for the URL it falls back to.
+    pub fn requeue(&self, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        state.outstanding -= 1;
+        let keys = HostKeys::of(job.url().unwrap_or_default());
+        state.parked.push_back((keys, Work::Job(job)));
+        self.released.notify_all();
+    }
+
+    /// Records that the work handed out is done with and won't come back.
+    pub fn complete(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.outstanding -= 1;
+        self.released.notify_all();
+    }
+
+    /// Returns the next work whose host has a free slot, or `None` once
+    /// the source is exhausted, nothing is left parked and no work may
+    /// come back anymore.
+    pub fn next(&self) -> Option<(Work, HostPermit<'_>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // Prefer parked items that became eligible
+            if let Some(pos) = state
+                .parked
+                .iter()
+                .position(|(keys, _)| self.has_room(&state, keys))
+            {
+                let (keys, work) = state.parked.remove(pos).unwrap();
+                return Some(self.hand_out(&mut state, keys, work));
+            }
+
+            if state.exhausted && state.parked.is_empty() && state.outstanding == 0 {
+                return None;
+            }
+
+            // Wait for a slot or a job to come back if we can't take more items aside
+            if state.exhausted || state.parked.len() >= MAX_PARKED {
+                state = self.released.wait_timeout(state, PARK_TIMEOUT).unwrap().0;
+                continue;
+            }
+
+            // Pull a new item, waiting a while for it only if nothing is parked
+            let received = if state.parked.is_empty() {
+                drop(state);
+                let received = self.work_rx.recv_timeout(PARK_TIMEOUT);
+                state = self.state.lock().unwrap();
+                received
+            } else {
+                self.work_rx.try_recv().map_err(|err| match err {
+                    TryRecvError::Empty => RecvTimeoutError::Timeout,
+                    TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                })
+            };
+
+            match received {
+                Ok(group) => {
+                    let keys = HostKeys::of(group.url());
+                    let work = Work::Group(group);
+                    if self.has_room(&state, &keys) {
+                        return Some(self.hand_out(&mut state, keys, work));
+                    }
+                    state.parked.push_back((keys, work));
+                }
+                Err(RecvTimeoutError::Timeout) if !state.parked.is_empty() => {
+                    state = self.released.wait_timeout(state, PARK_TIMEOUT).unwrap().0;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    state.exhausted = true;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(keys) = &self.keys {
+            self.scheduler.release(keys);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::mpsc, thread};
+
+    use crossbeam::channel::unbounded;
+    use rskachka::item::Item;
+
+    use super::*;
+
+    fn scheduler(urls: &[&str], host_limit: usize, domain_limit: usize) -> HostScheduler {
+        let (work_tx, work_rx) = unbounded();
+        for url in urls {
+            work_tx
+                .send(Group::new(Item {
+                    id: "1".to_string(),
+                    url: url.to_string(),
+                    fallbacks: Vec::new(),
+                    position: None,
+                    path: PathBuf::from(url),
+                    crop: None,
+                    original: None,
+                }))
+                .unwrap();
+        }
+        HostScheduler::new(work_rx, host_limit, domain_limit)
+    }
+
+    fn next(scheduler: &HostScheduler) -> (String, HostPermit<'_>) {
+        match scheduler.next() {
+            Some((Work::Group(group), permit)) => (group.url().to_string(), permit),
+            _ => panic!("expected a group"),
+        }
+    }
+
+    #[test]
+    fn parks_saturated_hosts() {
+        let scheduler = scheduler(
+            &["http://a.com/1", "http://a.com/2", "http://b.com/1"],
+            1,
+            0,
+        );
+        let (first, permit) = next(&scheduler);
+        assert_eq!(first, "http://a.com/1");
+        assert_eq!(next(&scheduler).0, "http://b.com/1");
+
+        // The parked group goes out once the slot is released
+        drop(permit);
+        assert_eq!(next(&scheduler).0, "http://a.com/2");
+        for _ in 0..3 {
+            scheduler.complete();
+        }
+        assert!(scheduler.next().is_none());
+    }
+
+    #[test]
+    fn limits_domains() {
+        let urls = ["http://x.a.com/1", "http://y.a.com/1", "http://c.org/1"];
+        let scheduler = scheduler(&urls, 0, 1);
+        let (first, permit) = next(&scheduler);
+        assert_eq!(first, "http://x.a.com/1");
+        assert_eq!(next(&scheduler).0, "http://c.org/1");
+        drop(permit);
+        assert_eq!(next(&scheduler).0, "http://y.a.com/1");
+    }
+
+    #[test]
+    fn stops_pulling_with_too_many_parked() {
+        let mut urls = vec!["http://a.com/"; MAX_PARKED + 2];
+        urls.push("http://b.com/");
+        let scheduler = scheduler(&urls, 1, 0);
+        let (_, permit) = next(&scheduler);
+        let scheduler = &scheduler;
+        thread::scope(|s| {
+            let (next_tx, next_rx) = mpsc::channel();
+            s.spawn(move || next_tx.send(next(scheduler).0).unwrap());
+
+            // The group for the other host is never read while a.com is saturated
+            assert!(next_rx.recv_timeout(PARK_TIMEOUT * 3).is_err());
+            drop(permit);
+            assert_eq!(next_rx.recv().unwrap(), "http://a.com/");
+        });
+    }
+
+    #[test]
+    fn waits_for_outstanding_work() {
+        let scheduler = scheduler(&["http://a.com/1"], 0, 0);
+        let (_, permit) = next(&scheduler);
+        drop(permit);
+        let scheduler = &scheduler;
+        thread::scope(|s| {
+            let (next_tx, next_rx) = mpsc::channel();
+            s.spawn(move || next_tx.send(scheduler.next().is_none()).unwrap());
+
+            // The work handed out may still come back until completed
+            assert!(next_rx.recv_timeout(PARK_TIMEOUT * 3).is_err());
+            scheduler.complete();
+            assert!(next_rx.recv().unwrap());
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use std::{cmp::max, fs, io::BufWriter, path::Path, sync::atomic::AtomicBool};
+use std::{cmp::max, fs, io::BufWriter, path::Path, sync::atomic::AtomicBool, time::Instant};
 
 use image::{
     codecs::{
@@ -7,10 +7,13 @@ use image::{
     },
     imageops, RgbaImage,
 };
-use log::info;
+use log::{debug, info};
+use rskachka::crop::Crop;
 use thiserror::Error;
 
-use crate::{abort::return_on_flag, saving::SavingSemaphore};
+use crate::{
+    abort::return_on_flag, args::Args, fetcher::is_past, saving::SavingSemaphore, sniff::Payload,
+};
 
 #[derive(Error, Debug)]
 pub enum ImagesError {
@@ -19,13 +22,42 @@ pub enum ImagesError {
 
     #[error("Image processing error: {0}")]
     Image(#[from] image::ImageError),
+
+    #[error("Deadline exceeded")]
+    Deadline,
+
+    #[error("Server returned {0}")]
+    NotAnImage(Payload),
+
+    #[error("Unsupported format {0}")]
+    Unsupported(Payload),
+
+    #[error("Crop {0} is outside the image")]
+    Crop(String),
+}
+
+/// How the saved images are resized and encoded.
+pub struct ImageOptions {
+    pub max_size: u32,
+    pub extension: String,
+    pub quality: u8,
+}
+
+impl From<&Args> for ImageOptions {
+    fn from(args: &Args) -> Self {
+        ImageOptions {
+            max_size: args.max_size,
+            extension: args.extension.clone(),
+            quality: args.quality,
+        }
+    }
 }
 
 fn is_bigger(image: &RgbaImage, max_size: u32) -> bool {
     max(image.width(), image.height()) > max_size
 }
 
-fn thumbnail(image: &mut RgbaImage, max_size: u32) -> RgbaImage {
+fn thumbnail(image: &RgbaImage, max_size: u32) -> RgbaImage {
     let (width, height) = image.dimensions();
     let scale = max_size as f32 / max(width, height) as f32;
     let new_width = (width as f32 * scale) as u32;
@@ -57,54 +89,76 @@ fn save_image(
     saving: &SavingSemaphore,
 ) -> Result<(), ImagesError> {
     saving.increment();
-    let mut writer = BufWriter::new(fs::File::create(path).map_err({
-        saving.decrement();
-        ImagesError::IO
-    })?);
-    match extension {
-        "webp" => image
-            .write_with_encoder(WebPEncoder::new_with_quality(
+    // Decrement once whatever happens, or Ctrl-C would wait forever
+    let result = (|| {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        match extension {
+            "webp" => image.write_with_encoder(WebPEncoder::new_with_quality(
                 &mut writer,
                 WebPQuality::lossy(quality),
-            ))
-            .map_err({
-                saving.decrement();
-                ImagesError::Image
-            })?,
-        "jpg" => image
-            .write_with_encoder(JpegEncoder::new_with_quality(&mut writer, quality))
-            .map_err({
-                saving.decrement();
-                ImagesError::Image
-            })?,
-        _ => panic!("Unsupported extension: {}", extension),
-    }
+            ))?,
+            "jpg" => {
+                image.write_with_encoder(JpegEncoder::new_with_quality(&mut writer, quality))?
+            }
+            _ => panic!("Unsupported extension: {}", extension),
+        }
+        Ok(())
+    })();
     saving.decrement();
-    Ok(())
+    result
 }
 
-pub fn save_bytes_as_image(
-    bytes: &[u8],
+/// Sniffs and decodes the bytes, failing early on what is not a supported image.
+pub fn decode_image(bytes: &[u8]) -> Result<RgbaImage, ImagesError> {
+    let payload = Payload::sniff(bytes);
+    let format = payload.image_format().ok_or(if payload.is_image() {
+        ImagesError::Unsupported(payload)
+    } else {
+        ImagesError::NotAnImage(payload)
+    })?;
+    debug!("Decoding {} bytes of {}", bytes.len(), payload);
+    Ok(image::load_from_memory_with_format(bytes, format)
+        .map_err(ImagesError::Image)?
+        .to_rgba8())
+}
+
+/// Crops, resizes and encodes a copy of the image to the path.
+pub fn save_as_image(
+    image: &RgbaImage,
+    crop: Option<&Crop>,
     path: &Path,
-    max_size: u32,
-    extension: &str,
-    quality: u8,
+    options: &ImageOptions,
+    deadline: Option<Instant>,
     stopped: &AtomicBool,
     saving: &SavingSemaphore,
 ) -> Result<(), ImagesError> {
     return_on_flag!(stopped, || info!("Shutting down..."));
-    let mut image = image::load_from_memory(bytes)
-        .map_err(ImagesError::Image)?
-        .to_rgba8();
-
-    return_on_flag!(stopped, || info!("Shutting down..."));
-    if is_bigger(&image, max_size) {
-        image = thumbnail(&mut image, max_size);
+    if is_past(deadline) {
+        return Err(ImagesError::Deadline);
     }
+    let cropped;
+    let image = match crop {
+        Some(crop) => {
+            let (x, y, width, height) = crop
+                .region(image.width(), image.height())
+                .ok_or_else(|| ImagesError::Crop(crop.to_string()))?;
+            cropped = imageops::crop_imm(image, x, y, width, height).to_image();
+            &cropped
+        }
+        None => image,
+    };
+    let mut image = if is_bigger(image, options.max_size) {
+        thumbnail(image, options.max_size)
+    } else {
+        image.clone()
+    };
 
     return_on_flag!(stopped, || info!("Shutting down..."));
     remove_transparency(&mut image);
 
     return_on_flag!(stopped, || info!("Shutting down..."));
-    save_image(&image, path, extension, quality, saving)
+    if is_past(deadline) {
+        return Err(ImagesError::Deadline);
+    }
+    save_image(&image, path, &options.extension, options.quality, saving)
 }
@@ -1,8 +1,29 @@
 mod abort;
 mod args;
+mod bandwidth;
+mod breaker;
+mod config;
+mod dedup;
+mod deferred;
+#[cfg(feature = "async")]
+mod engine;
 mod fetcher;
+mod group;
+mod headers;
+mod hosts;
 mod images;
+mod pool;
+mod proxy;
+mod queues;
+mod rate;
+mod refresh;
+mod retry;
+mod robots;
 mod saving;
+mod shared;
+mod sniff;
+mod stats;
+mod units;
 mod worker;
 
 use std::{
@@ -14,29 +35,45 @@ use std::{
         Arc,
     },
     thread,
+    time::Duration,
 };
 
 use clap::Parser;
 use clap_verbosity_flag::{LogLevel, Verbosity};
-use crossbeam::channel::{bounded, Receiver, Sender};
-use indicatif::ProgressBar;
-use log::{warn, Level};
+use crossbeam::channel::{bounded, unbounded, RecvTimeoutError, Sender};
+use indicatif::{HumanBytes, ProgressBar};
+use log::{info, log_enabled, warn, Level};
 use memmap2::Mmap;
-use rskachka::{maybe_create_progressbar, rslc};
+use rskachka::{
+    item::{Item, ItemParser},
+    maybe_create_progressbar,
+    rewrite::RewriteRules,
+    rslc,
+};
 
-use crate::abort::break_on_flag;
+use crate::abort::{break_on_flag, sleep_unless_stopped};
 use crate::args::Args;
+use crate::config::Config;
+#[cfg(feature = "async")]
+use crate::engine::AsyncEngine;
+use crate::group::Group;
+use crate::hosts::{HostScheduler, Work};
+use crate::queues::Queues;
 use crate::saving::SavingSemaphore;
-use crate::worker::Worker;
+use crate::shared::Shared;
+use crate::worker::{Download, Finished, Job, Outcome, ProcessError, Report, Worker};
+
+/// How often the queue depths are logged when not showing progress.
+const QUEUES_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
 fn parse_args() -> Result<Args> {
     let args = Args::parse();
     if args.progress && args.verbose.log_level().unwrap_or(Level::Error) > Level::Warn {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        Err(std::io::Error::other(
             "Choose either verbose logging or progress display, not both",
         ))
     } else {
+        args.cropping.validate().map_err(std::io::Error::other)?;
         Ok(args)
     }
 }
@@ -77,60 +114,211 @@ fn set_ctrl_c_handler(stopped: &Arc<AtomicBool>, saving: &Arc<SavingSemaphore>)
 
 fn launch_producer(
     source_file: File,
-    no_header: bool,
-    work_tx: Sender<csv::StringRecord>,
+    args: &Args,
+    rewrites: RewriteRules,
+    work_tx: Sender<Group>,
+    queues: &Arc<Queues>,
     stopped: &Arc<AtomicBool>,
     pb: &Option<ProgressBar>,
 ) {
+    let no_header = args.no_header;
+    let parser = ItemParser {
+        fields: args.fields.clone(),
+        url_fields: args.url_field.clone(),
+        path_field: args.path_field,
+        gallery: args.gallery.clone(),
+        canonical: args.canonical.clone(),
+        cropping: args.cropping.clone(),
+        rewrites,
+        hash_url: args.hash_url,
+        output_root: args.output_root.clone(),
+        originals_root: args.originals_root.clone(),
+        extension: args.extension.clone(),
+    };
+    let c_queues = Arc::clone(queues);
     let c_stopped = Arc::clone(stopped);
     let c_pb = pb.clone();
     thread::Builder::new()
         .name("producer".to_string())
         .stack_size(4 * 1024 * 1024)
         .spawn(move || {
+            // Consecutive records with the same URLs are fetched together
+            let mut group: Option<Group> = None;
             for record in csv::ReaderBuilder::new()
                 .has_headers(!no_header)
                 .from_reader(source_file)
                 .records()
             {
                 break_on_flag!(c_stopped, || warn!("Shutting down the producer..."));
-                match record {
-                    Ok(record) => {
-                        work_tx.send(record).unwrap();
-                    }
+                let record = match record {
+                    Ok(record) => record,
                     Err(e) => {
                         if let Some(c_pb) = &c_pb {
                             c_pb.inc(1);
                         }
                         warn!("Error reading record: {}", e);
+                        continue;
                     }
                 };
+                let items = parser.parse(&record);
+                if let Some(c_pb) = &c_pb {
+                    // Galleries yield several items per record
+                    match items.len() {
+                        0 => c_pb.inc(1),
+                        count => c_pb.inc_length(count as u64 - 1),
+                    }
+                }
+                for item in items {
+                    match item {
+                        Ok(item) => match &mut group {
+                            Some(group) if group.accepts(&item) => group.items.push(item),
+                            _ => {
+                                if let Some(full) = group.replace(Group::new(item)) {
+                                    c_queues.download.push();
+                                    work_tx.send(full).unwrap();
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            if let Some(c_pb) = &c_pb {
+                                c_pb.inc(1);
+                            }
+                            warn!("Error parsing record: {}", e);
+                        }
+                    };
+                }
+            }
+            if let Some(group) = group {
+                c_queues.download.push();
+                work_tx.send(group).unwrap();
             }
         })
         .unwrap();
 }
 
+fn progress_message(shared: &Shared) -> String {
+    let mut message = String::new();
+    let limiter = &shared.limiter;
+    if limiter.waited() > Duration::ZERO {
+        message += &format!(
+            ", throttled {} for {:.1?}",
+            limiter.waiting(),
+            limiter.waited()
+        );
+    }
+    if shared.bandwidth.received() > 0 {
+        message += &format!(", {}/s", HumanBytes(shared.bandwidth.throughput() as u64));
+    }
+    if shared.breaker.suspended() > 0 {
+        message += &format!(", suspended {} hosts", shared.breaker.suspended());
+    }
+    if !shared.deferred.is_empty() {
+        message += &format!(", deferred {}", shared.deferred.len());
+    }
+    if !shared.queues.is_empty() {
+        message += &format!(", queued {}", shared.queues);
+    }
+    if shared.stats.unchanged() > 0 {
+        message += &format!(", unchanged {}", shared.stats.unchanged());
+    }
+    if shared.pool.requests() > 0 {
+        message += &format!(", {}", shared.pool);
+    }
+    message
+}
+
+/// Records what became of the item, leaving what may pass for the next round if deferring.
+fn report(
+    item: Item,
+    result: std::result::Result<Outcome, ProcessError>,
+    defer: bool,
+    shared: &Shared,
+    pb: &Option<ProgressBar>,
+) {
+    let result = match result {
+        Err(err) if defer && err.is_retryable() => {
+            info!("Deferring {}: {}", item.url, err);
+            shared.deferred.push(item, err);
+            if let Some(pb) = &pb {
+                pb.set_message(progress_message(shared));
+            }
+            return;
+        }
+        result => result,
+    };
+    shared.stats.record(&result);
+    match result {
+        Ok(_) => {}
+        Err(err) if err.is_disallowed() => info!("{}", err),
+        Err(err) => warn!("{}", err),
+    }
+    if let Some(pb) = &pb {
+        pb.set_message(progress_message(shared));
+        pb.inc(1);
+    }
+}
+
+/// Runs the staged pipeline: the download workers fetch the images and queue
+/// them for the image workers, which decode and save them. The jobs falling
+/// back to another URL go back to the scheduler, to wait for a host slot.
 fn launch_workers(
-    worker_count: usize,
     args: &Args,
-    work_rx: &Receiver<csv::StringRecord>,
+    scheduler: &HostScheduler,
+    shared: &Shared,
     stopped: &Arc<AtomicBool>,
     saving: &Arc<SavingSemaphore>,
-    pb: &Option<ProgressBar>,
+    report: Report,
 ) {
+    let (images_tx, images_rx) = bounded::<(Job, Download)>(args.image_queue());
     thread::scope(|s| {
-        for i in 0..worker_count {
+        for i in 0..args.worker_count {
+            let images_tx = images_tx.clone();
             thread::Builder::new()
                 .name(format!("worker{}", i))
                 .stack_size(4 * 1024 * 1024)
                 .spawn_scoped(s, move || {
-                    let worker = Worker::from(args);
-                    while let Ok(record) = work_rx.recv() {
-                        if let Err(err) = worker.process(&record, stopped, saving) {
-                            warn!("{}", err);
+                    let worker = Worker::new(args, shared);
+                    while let Some((work, permit)) = scheduler.next() {
+                        shared.queues.download.pop();
+                        let mut job = match work {
+                            Work::Group(group) => worker.claim(group),
+                            Work::Job(job) => job,
+                        };
+                        let download = worker.download(&mut job, stopped);
+                        drop(permit);
+                        if let Download::Elsewhere = download {
+                            shared.queues.download.push();
+                            scheduler.requeue(job);
+                            continue;
                         }
-                        if let Some(pb) = &pb {
-                            pb.inc(1);
+                        shared.queues.image.push();
+                        images_tx.send((job, download)).unwrap();
+                    }
+                })
+                .unwrap();
+        }
+        drop(images_tx);
+
+        for i in 0..args.image_workers() {
+            let images_rx = images_rx.clone();
+            thread::Builder::new()
+                .name(format!("image{}", i))
+                .stack_size(4 * 1024 * 1024)
+                .spawn_scoped(s, move || {
+                    let worker = Worker::new(args, shared);
+                    for (job, download) in images_rx {
+                        shared.queues.image.pop();
+                        match worker.finish(job, download, stopped, saving) {
+                            Finished::Done(results) => {
+                                for (item, result) in results {
+                                    report(item, result);
+                                }
+                                scheduler.complete();
+                            }
+                            Finished::Refetch(job) => {
+                                shared.queues.download.push();
+                                scheduler.requeue(job);
+                            }
                         }
                     }
                 })
@@ -139,6 +327,24 @@ fn launch_workers(
     });
 }
 
+/// Logs the queue depths and the pool stats every few seconds until `run` returns,
+/// unless showing progress.
+fn with_queues_logged(shared: &Shared, pb: &Option<ProgressBar>, run: impl FnOnce()) {
+    if pb.is_some() || !log_enabled!(Level::Info) {
+        return run();
+    }
+    let (done_tx, done_rx) = bounded::<()>(0);
+    thread::scope(|s| {
+        s.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(QUEUES_LOG_INTERVAL) {
+                info!("Queued {}, {}", shared.queues, shared.pool);
+            }
+        });
+        run();
+        drop(done_tx);
+    });
+}
+
 fn main() -> Result<()> {
     // Get the arguments
     let args = parse_args()?;
@@ -146,6 +352,10 @@ fn main() -> Result<()> {
     // Set the log level
     init_logging(&args.verbose);
 
+    // Load the config file and the rewrite rules
+    let config = Config::load(args.config.as_deref())?;
+    let rewrites = RewriteRules::maybe_load(args.rewrite_rules.as_deref())?;
+
     // Calculate the source size
     let source_size = calculate_source_size(&args.source_path, args.no_header)?;
 
@@ -153,7 +363,9 @@ fn main() -> Result<()> {
     let source_file = open_source_file(&args.source_path)?;
 
     // Set up the communication
-    let (work_tx, work_rx) = bounded::<csv::StringRecord>(args.worker_count);
+    let (work_tx, work_rx) = bounded::<Group>(args.worker_count);
+    let scheduler = HostScheduler::new(work_rx, args.host_concurrency, args.domain_concurrency);
+    let shared = Shared::new(&args, &config)?;
     let stopped = Arc::new(AtomicBool::new(false));
     let saving = Arc::new(SavingSemaphore::new());
 
@@ -163,11 +375,213 @@ fn main() -> Result<()> {
     // Gracefully shutdown on Ctrl-C
     set_ctrl_c_handler(&stopped, &saving);
 
+    // Start the async engine if asked to
+    #[cfg(feature = "async")]
+    let engine = args
+        .async_in_flight
+        .map(|in_flight| AsyncEngine::new(&args, &shared, in_flight as usize))
+        .transpose()?;
+
+    // Process the scheduled groups with the engine or the workers
+    let process = |scheduler: &HostScheduler, defer: bool| {
+        let report = |item, result| report(item, result, defer, &shared, &pb);
+        with_queues_logged(&shared, &pb, || {
+            #[cfg(feature = "async")]
+            if let Some(engine) = &engine {
+                return engine.run(&args, scheduler, &shared, &stopped, &saving, &report);
+            }
+            launch_workers(&args, scheduler, &shared, &stopped, &saving, &report);
+        });
+    };
+
     // Launch the producer
-    launch_producer(source_file, args.no_header, work_tx, &stopped, &pb);
+    launch_producer(
+        source_file,
+        &args,
+        rewrites,
+        work_tx,
+        &shared.queues,
+        &stopped,
+        &pb,
+    );
 
     // Launch the workers
-    launch_workers(args.worker_count, &args, &work_rx, &stopped, &saving, &pb);
+    process(&scheduler, args.retry_rounds > 0);
+
+    // Retry the deferred records, waiting longer before each round
+    for round in 1..=args.retry_rounds {
+        if shared.deferred.is_empty() || stopped.load(Ordering::Relaxed) {
+            break;
+        }
+        let delay = Duration::from_secs(args.retry_round_delay)
+            .saturating_mul(2u32.saturating_pow(round - 1));
+        info!(
+            "Retrying {} deferred records in {:?} ({}/{})",
+            shared.deferred.len(),
+            delay,
+            round,
+            args.retry_rounds
+        );
+        if !sleep_unless_stopped(delay, &stopped) {
+            break;
+        }
+        shared.in_flight.forget_failed();
+        let (retry_tx, retry_rx) = unbounded::<Group>();
+        for group in shared.deferred.take_groups() {
+            shared.queues.download.push();
+            retry_tx.send(group).unwrap();
+        }
+        drop(retry_tx);
+        let scheduler =
+            HostScheduler::new(retry_rx, args.host_concurrency, args.domain_concurrency);
+        process(&scheduler, round < args.retry_rounds);
+    }
+
+    // Fail the records still deferred after the last round
+    let leftovers = shared.deferred.take();
+    if !leftovers.is_empty() {
+        info!("Giving up on {} deferred records", leftovers.len());
+    }
+    for (item, err) in leftovers {
+        report(item, Err(err), false, &shared, &pb);
+    }
+    info!("Done: {}", shared.stats);
+    info!(
+        "Downloaded {} at {}/s",
+        HumanBytes(shared.bandwidth.received()),
+        HumanBytes(shared.bandwidth.throughput() as u64)
+    );
+    info!("Connections: {}", shared.pool);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Cursor,
+        path::Path,
+        sync::{atomic::AtomicUsize, mpsc},
+    };
+
+    use clap::{CommandFactory, FromArgMatches};
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+    use url::Url;
+
+    use super::*;
+    use crate::worker::Outcome;
+
+    fn args(root: &Path) -> Args {
+        // The short -q of --quality clashes with --quiet, which debug builds assert on
+        let command = Args::command().mut_arg("quality", |arg| arg.short(None));
+        let root = root.to_string_lossy();
+        let matches = command.get_matches_from([
+            "rskachka",
+            "-s",
+            "source.csv",
+            "-o",
+            &root,
+            "-w",
+            "1",
+            "--image-workers",
+            "1",
+            "--host-concurrency",
+            "1",
+            "--retries",
+            "0",
+        ]);
+        Args::from_arg_matches(&matches).unwrap()
+    }
+
+    /// Records falling back from an unreachable host to a local file which
+    /// is no image, and then to one which is.
+    fn groups(root: &Path) -> Vec<Group> {
+        fs::remove_dir_all(root).ok();
+        fs::create_dir_all(root).unwrap();
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+        let url = |path: &Path| Url::from_file_path(path).unwrap().to_string();
+        (0..3)
+            .map(|i| {
+                let broken = root.join(format!("broken{}.png", i));
+                let usable = root.join(format!("usable{}.png", i));
+                fs::write(&broken, "<html></html>").unwrap();
+                fs::write(&usable, &png).unwrap();
+                Group::new(Item {
+                    id: i.to_string(),
+                    url: format!("http://127.0.0.1:1/{}.png", i),
+                    fallbacks: vec![url(&broken), url(&usable)],
+                    position: None,
+                    path: root.join("out").join(format!("{}.webp", i)),
+                    crop: None,
+                    original: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Runs the groups, returning how many items got saved unless it takes too long.
+    fn saved_in_time(
+        args: Args,
+        groups: Vec<Group>,
+        run: impl FnOnce(&Args, &HostScheduler, &Shared, Report) + Send + 'static,
+    ) -> usize {
+        let (saved_tx, saved_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (work_tx, work_rx) = unbounded();
+            for group in groups {
+                work_tx.send(group).unwrap();
+            }
+            drop(work_tx);
+            let scheduler =
+                HostScheduler::new(work_rx, args.host_concurrency, args.domain_concurrency);
+            let shared = Shared::new(&args, &Config::default()).unwrap();
+            let saved = AtomicUsize::new(0);
+            let report = |_, result| {
+                if let Ok(Outcome::Saved) = result {
+                    saved.fetch_add(1, Ordering::Relaxed);
+                }
+            };
+            run(&args, &scheduler, &shared, &report);
+            saved_tx.send(saved.into_inner()).ok();
+        });
+        saved_rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("The run got stuck")
+    }
+
+    #[test]
+    fn falls_back_under_host_limits() {
+        let root = std::env::temp_dir().join(format!("rskachka-pipeline-{}", process::id()));
+        let saved = saved_in_time(
+            args(&root),
+            groups(&root),
+            |args, scheduler, shared, report| {
+                let saving = Arc::new(SavingSemaphore::new());
+                launch_workers(args, scheduler, shared, &Arc::default(), &saving, report);
+            },
+        );
+        assert_eq!(saved, 3);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn falls_back_under_host_limits_async() {
+        let root = std::env::temp_dir().join(format!("rskachka-engine-{}", process::id()));
+        let saved = saved_in_time(
+            args(&root),
+            groups(&root),
+            |args, scheduler, shared, report| {
+                let saving = Arc::new(SavingSemaphore::new());
+                let engine = AsyncEngine::new(args, shared, 1).unwrap();
+                engine.run(args, scheduler, shared, &Arc::default(), &saving, report);
+            },
+        );
+        assert_eq!(saved, 3);
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::args::Args;
+
+/// Counts the requests and the connections opened for them, telling how
+/// well the connections are reused.
+#[derive(Default)]
+pub struct PoolStats {
+    requests: AtomicUsize,
+    connections: AtomicUsize,
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests over {} connections",
+            self.requests.load(Ordering::Relaxed),
+            self.connections.load(Ordering::Relaxed)
+        )
+    }
+}
+
+impl PoolStats {
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request made without a ureq agent.
+    #[cfg(feature = "async")]
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the requests, once per call.
+struct CountRequests(Arc<PoolStats>);
+
+impl ureq::Middleware for CountRequests {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        self.0.requests.fetch_add(1, Ordering::Relaxed);
+        next.handle(request)
+    }
+}
+
+/// Counts the new connections, as pooled ones get reused without resolving.
+struct CountConnections(Arc<PoolStats>);
+
+impl ureq::Resolver for CountConnections {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        self.0.connections.fetch_add(1, Ordering::Relaxed);
+        netloc.to_socket_addrs().map(Iterator::collect)
+    }
+}
+
+fn max_idle_per_host(args: &Args) -> usize {
+    match args.pool_max_idle_per_host {
+        0 => args.worker_count,
+        max => max,
+    }
+}
+
+/// The pool settings of the arguments, kept to build the agents anew.
+#[derive(Clone, Copy)]
+pub struct PoolConfig {
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_idle: usize,
+    max_idle_per_host: usize,
+    pub idle_timeout: Duration,
+}
+
+impl From<&Args> for PoolConfig {
+    fn from(args: &Args) -> Self {
+        PoolConfig {
+            connect_timeout: Duration::from_secs(args.connect_timeout),
+            timeout: Duration::from_secs(args.timeout),
+            max_idle: args.pool_max_idle,
+            max_idle_per_host: max_idle_per_host(args),
+            idle_timeout: Duration::from_secs(args.pool_idle_timeout),
+        }
+    }
+}
+
+/// Configures the agents shared by all workers, so they reuse each other's
+/// idle connections. The pool keeps idle connections until the servers close
+/// them, which is detected before reuse, or the agent is replaced for being idle.
+pub fn agent_builder(config: &PoolConfig, stats: &Arc<PoolStats>) -> ureq::AgentBuilder {
+    ureq::AgentBuilder::new()
+        .timeout_connect(config.connect_timeout)
+        .timeout_read(config.timeout)
+        .max_idle_connections(config.max_idle)
+        .max_idle_connections_per_host(config.max_idle_per_host)
+        .middleware(CountRequests(Arc::clone(stats)))
+        .resolver(CountConnections(Arc::clone(stats)))
+}
+
+/// Configures the async clients like the agents. HTTP/2 connections are
+/// multiplexed, so a single one per host may carry all its requests.
+/// There's no limit of idle connections for all hosts, so it only caps
+/// the one per host.
+#[cfg(feature = "async")]
+pub fn client_builder(args: &Args, stats: &Arc<PoolStats>) -> reqwest::ClientBuilder {
+    let max_idle_per_host = match (args.pool_max_idle_per_host, args.async_in_flight) {
+        (0, Some(in_flight)) => in_flight as usize,
+        _ => max_idle_per_host(args),
+    };
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(args.connect_timeout))
+        .read_timeout(Duration::from_secs(args.timeout))
+        .pool_max_idle_per_host(max_idle_per_host.min(args.pool_max_idle))
+        .pool_idle_timeout(Duration::from_secs(args.pool_idle_timeout))
+        // Counted when connecting, as IP addresses aren't resolved
+        .connector_layer(tower::util::MapRequestLayer::new({
+            let stats = Arc::clone(stats);
+            move |request| {
+                stats.connections.fetch_add(1, Ordering::Relaxed);
+                request
+            }
+        }))
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{args::Args, config::Config, hosts::host_matches};
+
+const DIRECT: &str = "direct";
+
+/// Where the requests to a host go: straight or through a proxy URL.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Route {
+    Direct,
+    Proxy(String),
+}
+
+impl From<&str> for Route {
+    fn from(value: &str) -> Self {
+        if value.is_empty() || value.eq_ignore_ascii_case(DIRECT) {
+            Route::Direct
+        } else {
+            Route::Proxy(value.to_string())
+        }
+    }
+}
+
+fn first_env(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| env::var(name).ok())
+        .filter(|value| !value.is_empty())
+}
+
+/// Chooses the route for each request, in order of precedence:
+/// per-host rules from the config, `NO_PROXY` and `--no-proxy` exclusions,
+/// then `--proxy` or the `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` variables.
+pub struct ProxyRoutes {
+    rules: Vec<(String, Route)>,
+    no_proxy: Vec<String>,
+    http: Route,
+    https: Route,
+    proxies: HashMap<String, ureq::Proxy>,
+}
+
+impl ProxyRoutes {
+    pub fn new(args: &Args, config: &Config) -> std::io::Result<Self> {
+        let rules: Vec<(String, Route)> = config
+            .hosts
+            .iter()
+            .filter_map(|rule| {
+                let route = Route::from(rule.proxy.as_deref()?);
+                Some((rule.host.to_lowercase(), route))
+            })
+            .collect();
+
+        let no_proxy = first_env(&["NO_PROXY", "no_proxy"])
+            .into_iter()
+            .chain(args.no_proxy.iter().cloned())
+            .flat_map(|list| {
+                list.split(',')
+                    .map(|entry| entry.trim().to_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|entry| !entry.is_empty())
+            .collect();
+
+        let (http, https) = match &args.proxy {
+            Some(proxy) => (Route::from(proxy.as_str()), Route::from(proxy.as_str())),
+            None => {
+                let all = first_env(&["ALL_PROXY", "all_proxy"]);
+                let http = first_env(&["HTTP_PROXY", "http_proxy"]).or(all.clone());
+                let https = first_env(&["HTTPS_PROXY", "https_proxy"]).or(all);
+                (
+                    Route::from(http.as_deref().unwrap_or(DIRECT)),
+                    Route::from(https.as_deref().unwrap_or(DIRECT)),
+                )
+            }
+        };
+
+        let mut proxies = HashMap::new();
+        for route in rules.iter().map(|(_, route)| route).chain([&http, &https]) {
+            if let Route::Proxy(proxy) = route {
+                let config = ureq::Proxy::new(proxy).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid proxy {}: {}", proxy, e),
+                    )
+                })?;
+                proxies.insert(proxy.clone(), config);
+            }
+        }
+
+        Ok(ProxyRoutes {
+            rules,
+            no_proxy,
+            http,
+            https,
+            proxies,
+        })
+    }
+
+    fn is_excluded(&self, host: &str) -> bool {
+        self.no_proxy.iter().any(|entry| {
+            let entry = entry.split(':').next().unwrap_or_default();
+            let entry = entry.trim_start_matches("*.").trim_start_matches('.');
+            entry == "*" || host_matches(host, entry)
+        })
+    }
+
+    /// Returns the route for a request to the host over the scheme.
+    pub fn route(&self, scheme: &str, host: &str) -> &Route {
+        if let Some((_, route)) = self
+            .rules
+            .iter()
+            .find(|(pattern, _)| host_matches(host, pattern))
+        {
+            return route;
+        }
+        if self.is_excluded(host) {
+            return &Route::Direct;
+        }
+        match scheme {
+            "https" => &self.https,
+            _ => &self.http,
+        }
+    }
+}
+
+struct IdleAgent {
+    agent: ureq::Agent,
+    used: Instant,
+}
+
+/// One agent per route, as ureq binds a proxy to an agent. As ureq has no idle
+/// timeout, an agent left unused for it is replaced, closing its connections.
+pub struct Agents {
+    agents: HashMap<Route, Mutex<IdleAgent>>,
+    proxies: HashMap<String, ureq::Proxy>,
+    builder: Box<dyn Fn() -> ureq::AgentBuilder + Send + Sync>,
+    idle_timeout: Duration,
+}
+
+impl Agents {
+    pub fn new(
+        routes: &ProxyRoutes,
+        builder: impl Fn() -> ureq::AgentBuilder + Send + Sync + 'static,
+        idle_timeout: Duration,
+    ) -> Self {
+        let mut agents = Agents {
+            agents: HashMap::new(),
+            proxies: routes.proxies.clone(),
+            builder: Box::new(builder),
+            idle_timeout,
+        };
+        let keys = routes.proxies.keys().cloned().map(Route::Proxy);
+        for route in keys.chain([Route::Direct]) {
+            let agent = agents.build(&route);
+            let used = Instant::now();
+            agents
+                .agents
+                .insert(route, Mutex::new(IdleAgent { agent, used }));
+        }
+        agents
+    }
+
+    fn build(&self, route: &Route) -> ureq::Agent {
+        match route {
+            Route::Direct => (self.builder)().build(),
+            Route::Proxy(proxy) => (self.builder)().proxy(self.proxies[proxy].clone()).build(),
+        }
+    }
+
+    pub fn get(&self, route: &Route) -> ureq::Agent {
+        let mut idle = self.agents[route].lock().unwrap();
+        if idle.used.elapsed() >= self.idle_timeout {
+            idle.agent = self.build(route);
+        }
+        idle.used = Instant::now();
+        idle.agent.clone()
+    }
+}
+
+/// One async client per route, as reqwest binds proxies to a client too.
+#[cfg(feature = "async")]
+pub struct Clients {
+    clients: HashMap<Route, reqwest::Client>,
+}
+
+#[cfg(feature = "async")]
+impl Clients {
+    pub fn new(
+        routes: &ProxyRoutes,
+        builder: impl Fn() -> reqwest::ClientBuilder,
+    ) -> std::io::Result<Self> {
+        let mut clients = HashMap::from([(
+            Route::Direct,
+            builder()
+                .no_proxy()
+                .build()
+                .map_err(std::io::Error::other)?,
+        )]);
+        for proxy in routes.proxies.keys() {
+            let config = reqwest::Proxy::all(proxy).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid proxy {}: {}", proxy, e),
+                )
+            })?;
+            let client = builder()
+                .proxy(config)
+                .build()
+                .map_err(std::io::Error::other)?;
+            clients.insert(Route::Proxy(proxy.clone()), client);
+        }
+        Ok(Clients { clients })
+    }
+
+    pub fn get(&self, route: &Route) -> &reqwest::Client {
+        &self.clients[route]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    fn counted(idle_timeout: Duration) -> (Agents, Arc<AtomicUsize>) {
+        let routes = ProxyRoutes {
+            rules: Vec::new(),
+            no_proxy: Vec::new(),
+            http: Route::Direct,
+            https: Route::Direct,
+            proxies: HashMap::new(),
+        };
+        let built = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&built);
+        let builder = move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            ureq::AgentBuilder::new()
+        };
+        (Agents::new(&routes, builder, idle_timeout), built)
+    }
+
+    #[test]
+    fn replaces_idle_agents() {
+        let (agents, built) = counted(Duration::from_secs(60));
+        agents.get(&Route::Direct);
+        agents.get(&Route::Direct);
+        assert_eq!(built.load(Ordering::Relaxed), 1);
+
+        let (agents, built) = counted(Duration::ZERO);
+        agents.get(&Route::Direct);
+        agents.get(&Route::Direct);
+        assert_eq!(built.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The number of entries waiting in front of a stage.
+#[derive(Default)]
+pub struct Depth(AtomicUsize);
+
+impl Depth {
+    pub fn push(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pop(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Depths of the queues between the pipeline stages: the groups waiting
+/// for a download, and the downloaded images waiting to be decoded and saved.
+#[derive(Default)]
+pub struct Queues {
+    pub download: Depth,
+    pub image: Depth,
+}
+
+impl Queues {
+    pub fn is_empty(&self) -> bool {
+        self.download.get() == 0 && self.image.get() == 0
+    }
+}
+
+impl fmt::Display for Queues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} to download, {} to save",
+            self.download.get(),
+            self.image.get()
+        )
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{abort::sleep_unless_stopped, hosts::host_matches};
+
+/// A number of events allowed per second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate(pub f64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parses rates like `20`, `20/s`, `300/m` or `1000/h`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, period) = s.split_once('/').unwrap_or((s, "s"));
+        let count = count
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid rate {:?}: {}", s, e))?;
+        let seconds = match period.trim() {
+            "s" | "sec" => 1.0,
+            "m" | "min" => 60.0,
+            "h" | "hour" => 3600.0,
+            other => return Err(format!("Invalid rate period {:?}", other)),
+        };
+        if count <= 0.0 || !count.is_finite() {
+            return Err(format!("Rate must be positive: {:?}", s));
+        }
+        Ok(Rate(count / seconds))
+    }
+}
+
+/// A rate applied to a host and its subdomains.
+#[derive(Clone, Debug)]
+pub struct HostRate {
+    pub host: String,
+    pub rate: Rate,
+}
+
+impl FromStr for HostRate {
+    type Err = String;
+
+    /// Parses rules like `cdn.example.com=5/s`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, rate) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected HOST=RATE, got {:?}", s))?;
+        Ok(HostRate {
+            host: host.trim().to_lowercase(),
+            rate: rate.parse()?,
+        })
+    }
+}
+
+/// A classic token bucket which lets callers reserve tokens in advance,
+/// so concurrent waiters are served in order of arrival.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket holding up to one second worth of tokens.
+    pub fn new(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Changes the refill rate, keeping the tokens accumulated so far.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.capacity = rate.max(1.0);
+        self.tokens = self.tokens.min(self.capacity);
+    }
+
+    /// Takes the tokens and returns how long to wait before using them.
+    pub fn reserve(&mut self, tokens: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        self.tokens -= tokens;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            // Saturating, as the rate may be tiny or zero
+            Duration::try_from_secs_f64(-self.tokens / self.rate).unwrap_or(Duration::MAX)
+        }
+    }
+}
+
+/// Per-host request rate limiter shared by all workers.
+pub struct RateLimiter {
+    default: Option<Rate>,
+    rules: Vec<HostRate>,
+    dynamic: Mutex<HashMap<String, Rate>>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    waiting: AtomicUsize,
+    waited: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(default: Option<Rate>, rules: Vec<HostRate>) -> Self {
+        RateLimiter {
+            default,
+            rules,
+            dynamic: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+            waiting: AtomicUsize::new(0),
+            waited: AtomicU64::new(0),
+        }
+    }
+
+    /// Lowers the rate for a single host, e.g. following its `Crawl-delay`.
+    pub fn limit_host(&self, host: &str, rate: Rate) {
+        self.dynamic
+            .lock()
+            .unwrap()
+            .entry(host.to_string())
+            .and_modify(|current| current.0 = current.0.min(rate.0))
+            .or_insert(rate);
+    }
+
+    /// Finds the bucket key and rate which apply to the host,
+    /// the rule for the most specific host winning.
+    fn lookup(&self, host: &str) -> Option<(String, Rate)> {
+        let matched = self
+            .rules
+            .iter()
+            .filter(|rule| host_matches(host, &rule.host))
+            .reduce(|best, rule| {
+                if rule.host.len() > best.host.len() {
+                    rule
+                } else {
+                    best
+                }
+            })
+            .map(|rule| (rule.host.clone(), rule.rate))
+            .or_else(|| self.default.map(|rate| (host.to_string(), rate)));
+        match self.dynamic.lock().unwrap().get(host) {
+            Some(&rate) => match matched {
+                Some((key, current)) if current.0 <= rate.0 => Some((key, current)),
+                _ => Some((host.to_string(), rate)),
+            },
+            None => matched,
+        }
+    }
+
+    /// Reserves a request to the host, returning how long to wait before making it.
+    pub fn reserve(&self, host: &str) -> Duration {
+        let Some((key, rate)) = self.lookup(host) else {
+            return Duration::ZERO;
+        };
+        self.buckets
+            .lock()
+            .unwrap()
+            .entry(key)
+            .and_modify(|bucket| {
+                if bucket.rate != rate.0 {
+                    bucket.set_rate(rate.0)
+                }
+            })
+            .or_insert_with(|| TokenBucket::new(rate.0))
+            .reserve(1.0)
+    }
+
+    /// Counts a request as held back until the guard is dropped.
+    pub fn hold(&self) -> Held<'_> {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        Held {
+            limiter: self,
+            since: Instant::now(),
+        }
+    }
+
+    /// Blocks until a request to the host is allowed and returns the time
+    /// spent waiting. Returns early if the flag gets set.
+    pub fn acquire(&self, host: &str, stopped: &AtomicBool) -> Duration {
+        let delay = self.reserve(host);
+        if delay == Duration::ZERO {
+            return Duration::ZERO;
+        }
+        let held = self.hold();
+        sleep_unless_stopped(delay, stopped);
+        held.since.elapsed()
+    }
+
+    /// The number of requests currently held back.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// The total time requests have been held back for.
+    pub fn waited(&self) -> Duration {
+        Duration::from_millis(self.waited.load(Ordering::Relaxed))
+    }
+}
+
+/// A request held back by the limiter, counted until dropped.
+pub struct Held<'a> {
+    limiter: &'a RateLimiter,
+    since: Instant,
+}
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        self.limiter.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.limiter
+            .waited
+            .fetch_add(self.since.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: &str, rate: f64) -> HostRate {
+        HostRate {
+            host: host.to_string(),
+            rate: Rate(rate),
+        }
+    }
+
+    #[test]
+    fn prefers_most_specific_host_rule() {
+        for rules in [
+            vec![rule("example.com", 10.0), rule("a.example.com", 1.0)],
+            vec![rule("a.example.com", 1.0), rule("example.com", 10.0)],
+        ] {
+            let limiter = RateLimiter::new(Some(Rate(100.0)), rules);
+            assert_eq!(
+                limiter.lookup("x.a.example.com"),
+                Some(("a.example.com".to_string(), Rate(1.0)))
+            );
+            assert_eq!(
+                limiter.lookup("b.example.com"),
+                Some(("example.com".to_string(), Rate(10.0)))
+            );
+            assert_eq!(
+                limiter.lookup("other.com"),
+                Some(("other.com".to_string(), Rate(100.0)))
+            );
+        }
+    }
+
+    #[test]
+    fn saturates_delays_of_tiny_rates() {
+        for rate in [0.0, 1e-300] {
+            let mut bucket = TokenBucket::new(rate);
+            assert_eq!(bucket.reserve(1.0), Duration::ZERO);
+            assert_eq!(bucket.reserve(1.0), Duration::MAX);
+        }
+    }
+
+    #[test]
+    fn counts_time_actually_waited() {
+        let limiter = RateLimiter::new(Some(Rate(0.1)), Vec::new());
+        let stopped = AtomicBool::new(false);
+        assert_eq!(limiter.acquire("a.com", &stopped), Duration::ZERO);
+
+        // The next request would wait for 10 seconds, but gets stopped
+        stopped.store(true, Ordering::Relaxed);
+        let waited = limiter.acquire("a.com", &stopped);
+        assert!(waited < Duration::from_secs(1));
+        assert!(limiter.waited() < Duration::from_secs(1));
+        assert_eq!(limiter.waiting(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+const STORE_DIR: &str = ".validators";
+const SOURCE_PREFIX: &str = "Source: ";
+
+/// The cache validators a server sent along with a response.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_response(response: &ureq::Response) -> Self {
+        Self::from_headers(|name| response.header(name))
+    }
+
+    /// Reads the validators with a lookup of the response headers by name.
+    pub fn from_headers<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Self {
+        Validators {
+            etag: header("ETag").map(str::to_string),
+            last_modified: header("Last-Modified").map(str::to_string),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// The headers making a request conditional on the resource having changed.
+    pub fn conditions(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("If-None-Match", &self.etag),
+            ("If-Modified-Since", &self.last_modified),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+    }
+
+    /// Makes the request conditional on the resource having changed.
+    pub fn apply(&self, request: ureq::Request) -> ureq::Request {
+        self.conditions()
+            .fold(request, |request, (name, value)| request.set(name, value))
+    }
+
+    /// Parses the `Name: value` lines written by `Display`.
+    fn parse(content: &str) -> Self {
+        let mut validators = Validators::default();
+        for line in content.lines() {
+            match line.split_once(':') {
+                Some((name, value)) if name.eq_ignore_ascii_case("etag") => {
+                    validators.etag = Some(value.trim().to_string())
+                }
+                Some((name, value)) if name.eq_ignore_ascii_case("last-modified") => {
+                    validators.last_modified = Some(value.trim().to_string())
+                }
+                _ => {}
+            }
+        }
+        validators
+    }
+}
+
+impl fmt::Display for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(etag) = &self.etag {
+            writeln!(f, "ETag: {}", etag)?;
+        }
+        if let Some(last_modified) = &self.last_modified {
+            writeln!(f, "Last-Modified: {}", last_modified)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the validators of the saved images in a tree mirroring the output root.
+pub struct ValidatorStore {
+    output_root: PathBuf,
+    root: PathBuf,
+}
+
+impl ValidatorStore {
+    pub fn new(output_root: &str) -> Self {
+        ValidatorStore {
+            output_root: PathBuf::from(output_root),
+            root: Path::new(output_root).join(STORE_DIR),
+        }
+    }
+
+    fn sidecar(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.output_root)
+            .ok()
+            .map(|relative| self.root.join(relative).with_extension("http"))
+    }
+
+    /// The validators stored for the image, if it was downloaded from the URL.
+    pub fn load(&self, path: &Path, url: &str) -> Validators {
+        let Some(content) = self
+            .sidecar(path)
+            .and_then(|sidecar| fs::read_to_string(sidecar).ok())
+        else {
+            return Validators::default();
+        };
+        let source = content
+            .lines()
+            .find_map(|line| line.strip_prefix(SOURCE_PREFIX));
+        if source.is_some_and(|source| source != url) {
+            return Validators::default();
+        }
+        Validators::parse(&content)
+    }
+
+    /// Stores the validators for the image downloaded from the URL,
+    /// forgetting the old ones if there are none.
+    pub fn store(&self, path: &Path, url: &str, validators: &Validators) -> std::io::Result<()> {
+        let Some(sidecar) = self.sidecar(path) else {
+            return Ok(());
+        };
+        if validators.is_empty() {
+            return match fs::remove_file(sidecar) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        if let Some(parent) = sidecar.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(sidecar, format!("{}{}\n{}", SOURCE_PREFIX, url, validators))
+    }
+}
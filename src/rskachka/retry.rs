@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+use crate::{args::Args, fetcher::FetchError};
+
+/// Exponential backoff settings for transient request failures.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the retry following the given zero-based attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0) * fastrand::f64();
+        exponential.mul_f64(1.0 - jitter)
+    }
+
+    /// Returns the delay before retrying the failed attempt, or `None` to give up:
+    /// when out of retries, the error is permanent, or the wait would pass the deadline.
+    pub fn retry_delay(
+        &self,
+        err: &FetchError,
+        attempt: u32,
+        deadline: Option<Instant>,
+    ) -> Option<Duration> {
+        if attempt >= self.retries || !err.is_retryable() {
+            return None;
+        }
+        let delay = match err.retry_after() {
+            Some(delay) if delay > self.max_delay => return None,
+            Some(delay) => delay,
+            None => self.delay(attempt),
+        };
+        match deadline {
+            Some(deadline) if Instant::now() + delay >= deadline => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+impl From<&Args> for RetryPolicy {
+    fn from(args: &Args) -> Self {
+        RetryPolicy {
+            retries: args.retries,
+            base_delay: Duration::from_millis(args.retry_delay),
+            max_delay: Duration::from_millis(args.retry_max_delay),
+            jitter: args.retry_jitter,
+        }
+    }
+}
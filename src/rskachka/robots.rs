@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use url::Url;
+
+use crate::{
+    fetcher::{FetchError, HttpFetcher},
+    rate::Rate,
+};
+
+const MAX_ROBOTS_SIZE: u64 = 500 * 1024;
+/// How long the URLs of an origin fail before its robots.txt is tried again.
+const ROBOTS_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Longer crawl delays are taken as this many seconds.
+const MAX_CRAWL_DELAY: f64 = 300.0;
+
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Rule {
+    /// Matches the path against a pattern with `*` wildcards and an optional `$` anchor.
+    fn matches(&self, path: &str) -> bool {
+        let (pattern, anchored) = match self.pattern.strip_suffix('$') {
+            Some(pattern) => (pattern, true),
+            None => (self.pattern.as_str(), false),
+        };
+        let mut parts = pattern.split('*');
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = path.strip_prefix(first) else {
+            return false;
+        };
+        let mut parts = parts.peekable();
+        if parts.peek().is_none() {
+            return !anchored || rest.is_empty();
+        }
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() && anchored {
+                return rest.ends_with(part);
+            }
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// The rules of a robots.txt group applying to our user-agent.
+#[derive(Default)]
+pub struct RobotsRules {
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+impl RobotsRules {
+    fn allow_all() -> Self {
+        RobotsRules::default()
+    }
+
+    /// Parses robots.txt, keeping the group for the token or the `*` fallback.
+    pub fn parse(content: &str, token: &str) -> Self {
+        let token = token.to_lowercase();
+        let mut specific = None::<RobotsRules>;
+        let mut fallback = None::<RobotsRules>;
+        let mut agents = Vec::<String>::new();
+        let mut current = RobotsRules::default();
+        let mut in_rules = false;
+
+        let mut flush = |agents: &mut Vec<String>, current: &mut RobotsRules| {
+            let group = std::mem::take(current);
+            if agents.contains(&token) {
+                specific
+                    .get_or_insert_with(RobotsRules::default)
+                    .merge(group);
+            } else if agents.iter().any(|agent| agent == "*") {
+                fallback
+                    .get_or_insert_with(RobotsRules::default)
+                    .merge(group);
+            }
+            agents.clear();
+        };
+
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim().to_lowercase().as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        flush(&mut agents, &mut current);
+                        in_rules = false;
+                    }
+                    agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" if !value.is_empty() => {
+                    in_rules = true;
+                    current.rules.push(Rule {
+                        allow: key.trim().eq_ignore_ascii_case("allow"),
+                        pattern: value.to_string(),
+                    });
+                }
+                "allow" | "disallow" => in_rules = true,
+                "crawl-delay" => {
+                    in_rules = true;
+                    current.crawl_delay = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|delay| delay.is_finite() && *delay > 0.0)
+                        .map(|delay| delay.min(MAX_CRAWL_DELAY));
+                }
+                _ => {}
+            }
+        }
+        flush(&mut agents, &mut current);
+
+        specific.or(fallback).unwrap_or_default()
+    }
+
+    fn merge(&mut self, other: RobotsRules) {
+        self.rules.extend(other.rules);
+        self.crawl_delay = self.crawl_delay.or(other.crawl_delay);
+    }
+
+    /// Whether the path is allowed, the longest matching rule winning.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+enum Entry {
+    Rules(RobotsRules),
+    /// robots.txt couldn't be downloaded, which is tried again after the instant
+    Failed(Instant, String),
+}
+
+/// Downloads and caches robots.txt per origin.
+pub struct RobotsCache {
+    token: String,
+    origins: Mutex<HashMap<String, Arc<Mutex<Option<Entry>>>>>,
+}
+
+impl RobotsCache {
+    pub fn new(token: &str) -> Self {
+        RobotsCache {
+            token: token.to_string(),
+            origins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Missing robots.txt allows everything. Timeouts, connection failures and
+    /// server errors are transient, so they fail without deciding anything.
+    fn download(
+        &self,
+        origin: &str,
+        host: &str,
+        fetcher: &HttpFetcher,
+    ) -> Result<RobotsRules, String> {
+        let robots_url = format!("{}/robots.txt", origin);
+        match fetcher.request(&robots_url, host).call() {
+            Ok(response) => {
+                let mut content = String::new();
+                response
+                    .into_reader()
+                    .take(MAX_ROBOTS_SIZE)
+                    .read_to_string(&mut content)
+                    .map_err(|e| e.to_string())?;
+                Ok(RobotsRules::parse(&content, &self.token))
+            }
+            Err(ureq::Error::Status(code, _)) if (400..500).contains(&code) => {
+                Ok(RobotsRules::allow_all())
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Whether the URL may be fetched, downloading robots.txt on first use
+    /// and applying its `Crawl-delay` to the host. Fails with a retryable
+    /// error while robots.txt can't be downloaded.
+    pub fn is_allowed(&self, url: &str, fetcher: &HttpFetcher) -> Result<bool, FetchError> {
+        let Ok(url) = Url::parse(url) else {
+            return Ok(true);
+        };
+        let Some(host) = url.host_str() else {
+            return Ok(true);
+        };
+        let origin = url.origin().ascii_serialization();
+        let slot = Arc::clone(
+            self.origins
+                .lock()
+                .unwrap()
+                .entry(origin.clone())
+                .or_default(),
+        );
+        // Held while downloading, so each origin is downloaded by one worker
+        let mut entry = slot.lock().unwrap();
+        let expired = match &*entry {
+            Some(Entry::Rules(_)) => false,
+            Some(Entry::Failed(until, _)) => Instant::now() >= *until,
+            None => true,
+        };
+        if expired {
+            *entry = Some(match self.download(&origin, host, fetcher) {
+                Ok(rules) => {
+                    if let Some(delay) = rules.crawl_delay {
+                        debug!(
+                            "Crawl-delay for {} is {:?}",
+                            host,
+                            Duration::from_secs_f64(delay)
+                        );
+                        fetcher.limiter().limit_host(host, Rate(1.0 / delay));
+                    }
+                    Entry::Rules(rules)
+                }
+                Err(e) => {
+                    info!(
+                        "Can't get robots.txt of {}, retrying in {:?}: {}",
+                        origin, ROBOTS_RETRY_DELAY, e
+                    );
+                    Entry::Failed(Instant::now() + ROBOTS_RETRY_DELAY, e)
+                }
+            });
+        }
+        match entry.as_ref().unwrap() {
+            Entry::Rules(rules) => {
+                let path = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                Ok(rules.is_allowed(&path))
+            }
+            Entry::Failed(_, e) => Err(FetchError::RobotsUnavailable(origin, e.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_patterns() {
+        for (pattern, path, expected) in [
+            ("/img", "/img", true),
+            ("/img", "/img/a.png", true),
+            ("/img", "/im", false),
+            ("/img", "/x/img", false),
+            ("/img$", "/img", true),
+            ("/img$", "/img/a.png", false),
+            ("*", "/anything", true),
+            ("/*.png", "/a/b.png", true),
+            ("/*.png", "/a/b.png?x=1", true),
+            ("/*.png", "/a/b.jpg", false),
+            ("/*.png$", "/a/b.png", true),
+            ("/*.png$", "/a/b.png?x=1", false),
+            ("/a*b*c", "/axbyc", true),
+            ("/a*b*c", "/axcyb", false),
+            ("/a*a$", "/a", false),
+            ("/a*a$", "/aa", true),
+            ("/a**b", "/ab", true),
+        ] {
+            let rule = Rule {
+                allow: true,
+                pattern: pattern.to_string(),
+            };
+            assert_eq!(rule.matches(path), expected, "{} on {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn longest_match_wins() {
+        let rules = RobotsRules::parse(
+            "User-agent: *\n\
+             Disallow: /img\n\
+             Allow: /img/public\n\
+             Disallow: /img/public/private\n\
+             Allow: /same\n\
+             Disallow: /same\n",
+            "rskachka",
+        );
+        for (path, expected) in [
+            ("/", true),
+            ("/img/a.png", false),
+            ("/img/public/a.png", true),
+            ("/img/public/private/a.png", false),
+            ("/same", true),
+        ] {
+            assert_eq!(rules.is_allowed(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn selects_group() {
+        let content = "User-agent: other\n\
+                       Disallow: /other\n\
+                       \n\
+                       User-agent: *\n\
+                       Disallow: /all\n\
+                       Crawl-delay: 5\n\
+                       \n\
+                       User-agent: Bot\n\
+                       User-agent: RsKachka\n\
+                       Disallow: /ours\n\
+                       \n\
+                       User-agent: rskachka\n\
+                       Disallow: /more # merged\n";
+        for (token, path, expected) in [
+            ("rskachka", "/ours", false),
+            ("rskachka", "/more", false),
+            ("rskachka", "/all", true),
+            ("bot", "/ours", false),
+            ("bot", "/more", true),
+            ("unknown", "/all", false),
+            ("unknown", "/ours", true),
+            ("other", "/other", false),
+            ("other", "/all", true),
+        ] {
+            let rules = RobotsRules::parse(content, token);
+            assert_eq!(rules.is_allowed(path), expected, "{} on {}", token, path);
+        }
+        assert_eq!(
+            RobotsRules::parse(content, "unknown").crawl_delay,
+            Some(5.0)
+        );
+        assert_eq!(RobotsRules::parse(content, "rskachka").crawl_delay, None);
+    }
+
+    #[test]
+    fn bounds_crawl_delay() {
+        for (value, expected) in [
+            ("0.5", Some(0.5)),
+            ("1e300", Some(MAX_CRAWL_DELAY)),
+            ("inf", None),
+            ("NaN", None),
+            ("0", None),
+            ("-1", None),
+            ("soon", None),
+        ] {
+            let content = format!("User-agent: *\nCrawl-delay: {}\n", value);
+            let rules = RobotsRules::parse(&content, "rskachka");
+            assert_eq!(rules.crawl_delay, expected, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    args::Args,
+    bandwidth::BandwidthLimiter,
+    breaker::CircuitBreaker,
+    config::Config,
+    dedup::InFlight,
+    deferred::Deferred,
+    fetcher::S3Settings,
+    headers::HeaderRules,
+    pool::{self, PoolConfig, PoolStats},
+    proxy::{Agents, ProxyRoutes},
+    queues::Queues,
+    rate::RateLimiter,
+    robots::RobotsCache,
+    stats::Stats,
+};
+
+/// State shared by all the workers of a run.
+pub struct Shared {
+    pub limiter: Arc<RateLimiter>,
+    pub bandwidth: Arc<BandwidthLimiter>,
+    pub breaker: Arc<CircuitBreaker>,
+    pub robots: Option<Arc<RobotsCache>>,
+    pub headers: Arc<HeaderRules>,
+    pub routes: Arc<ProxyRoutes>,
+    pub agents: Arc<Agents>,
+    pub pool: Arc<PoolStats>,
+    pub s3: Arc<S3Settings>,
+    pub in_flight: Arc<InFlight>,
+    pub deferred: Deferred,
+    pub queues: Arc<Queues>,
+    pub stats: Stats,
+}
+
+impl Shared {
+    pub fn new(args: &Args, config: &Config) -> std::io::Result<Self> {
+        let routes = ProxyRoutes::new(args, config)?;
+        let s3 = S3Settings::new(&config.s3)?;
+        let pool = Arc::new(PoolStats::default());
+        let pool_config = PoolConfig::from(args);
+        let agents = Agents::new(
+            &routes,
+            {
+                let pool = Arc::clone(&pool);
+                move || pool::agent_builder(&pool_config, &pool)
+            },
+            pool_config.idle_timeout,
+        );
+        Ok(Shared {
+            limiter: Arc::new(RateLimiter::new(args.rate_limit, args.host_rate.clone())),
+            bandwidth: Arc::new(BandwidthLimiter::new(
+                args.bandwidth,
+                args.host_bandwidth.clone(),
+            )),
+            breaker: Arc::new(CircuitBreaker::new(
+                args.breaker_failures,
+                Duration::from_secs(args.breaker_cooldown),
+            )),
+            robots: args
+                .robots
+                .then(|| Arc::new(RobotsCache::new(&args.robots_agent))),
+            headers: Arc::new(HeaderRules::new(args, config)),
+            routes: Arc::new(routes),
+            agents: Arc::new(agents),
+            pool,
+            s3: Arc::new(s3),
+            in_flight: Arc::new(InFlight::default()),
+            deferred: Deferred::default(),
+            queues: Arc::new(Queues::default()),
+            stats: Stats::default(),
+        })
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+
+use image::ImageFormat;
+
+const TEXT_PREFIX_LEN: usize = 1024;
+
+/// The kind of a payload as told by its first bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Tiff,
+    Bmp,
+    Avif,
+    Heic,
+    Ico,
+    Svg,
+    Html,
+    Xml,
+    Json,
+    Empty,
+    Unknown,
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Payload::Jpeg => "JPEG",
+            Payload::Png => "PNG",
+            Payload::Gif => "GIF",
+            Payload::WebP => "WebP",
+            Payload::Tiff => "TIFF",
+            Payload::Bmp => "BMP",
+            Payload::Avif => "AVIF",
+            Payload::Heic => "HEIC",
+            Payload::Ico => "ICO",
+            Payload::Svg => "SVG",
+            Payload::Html => "HTML",
+            Payload::Xml => "XML",
+            Payload::Json => "JSON",
+            Payload::Empty => "an empty body",
+            Payload::Unknown => "an unknown format",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Looks for AVIF and HEIF brands in an ISO BMFF `ftyp` box.
+fn sniff_ftyp(bytes: &[u8]) -> Option<Payload> {
+    if bytes.len() < 12 || &bytes[4..8] != b"ftyp" {
+        return None;
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let end = size.clamp(12, bytes.len());
+    // The major brand, then the compatible ones after the minor version
+    let brands = std::iter::once(&bytes[8..12]).chain(bytes[16.min(end)..end].chunks_exact(4));
+    let mut payload = None;
+    for brand in brands {
+        match brand {
+            b"avif" | b"avis" => return Some(Payload::Avif),
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1" => {
+                payload = Some(Payload::Heic)
+            }
+            _ => {}
+        }
+    }
+    payload
+}
+
+/// Tells markup and JSON apart by the first meaningful characters.
+fn sniff_text(bytes: &[u8]) -> Payload {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let prefix = &bytes[..bytes.len().min(TEXT_PREFIX_LEN)];
+    let text = String::from_utf8_lossy(prefix).to_ascii_lowercase();
+    let text = text.trim_start();
+    if text.starts_with('{') || text.starts_with('[') {
+        Payload::Json
+    } else if text.starts_with("<svg") {
+        Payload::Svg
+    } else if text.starts_with("<?xml") || text.starts_with("<!--") {
+        if text.contains("<svg") {
+            Payload::Svg
+        } else if text.contains("<html") {
+            Payload::Html
+        } else {
+            Payload::Xml
+        }
+    } else if text.starts_with('<') {
+        Payload::Html
+    } else {
+        Payload::Unknown
+    }
+}
+
+impl Payload {
+    /// Classifies the payload by its magic bytes.
+    pub fn sniff(bytes: &[u8]) -> Self {
+        match bytes {
+            [] => Payload::Empty,
+            [0xFF, 0xD8, 0xFF, ..] => Payload::Jpeg,
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Payload::Png,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Payload::Gif,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Payload::WebP,
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Payload::Tiff,
+            [b'B', b'M', ..] => Payload::Bmp,
+            [0x00, 0x00, 0x01, 0x00, ..] => Payload::Ico,
+            _ => sniff_ftyp(bytes).unwrap_or_else(|| sniff_text(bytes)),
+        }
+    }
+
+    /// Whether the payload is an image at all, supported or not.
+    pub fn is_image(self) -> bool {
+        !matches!(
+            self,
+            Payload::Html | Payload::Xml | Payload::Json | Payload::Empty | Payload::Unknown
+        )
+    }
+
+    /// The decoder to use for the payload, if it is supported.
+    pub fn image_format(self) -> Option<ImageFormat> {
+        match self {
+            Payload::Jpeg => Some(ImageFormat::Jpeg),
+            Payload::Png => Some(ImageFormat::Png),
+            Payload::Gif => Some(ImageFormat::Gif),
+            Payload::WebP => Some(ImageFormat::WebP),
+            Payload::Tiff => Some(ImageFormat::Tiff),
+            Payload::Bmp => Some(ImageFormat::Bmp),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ftyp(brands: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * (brands.len() - 1);
+        let mut bytes = (size as u32).to_be_bytes().to_vec();
+        bytes.extend(b"ftyp");
+        bytes.extend(brands[0]);
+        bytes.extend([0, 0, 0, 0]);
+        brands[1..].iter().for_each(|brand| bytes.extend(*brand));
+        bytes
+    }
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        for (bytes, expected) in [
+            (&b""[..], Payload::Empty),
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF", Payload::Jpeg),
+            (b"\x89PNG\r\n\x1A\n\x00\x00", Payload::Png),
+            (b"GIF89a\x01\x00", Payload::Gif),
+            (b"GIF87a\x01\x00", Payload::Gif),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", Payload::WebP),
+            (b"RIFF\x24\x00\x00\x00WAVEfmt ", Payload::Unknown),
+            (b"II*\x00\x08\x00", Payload::Tiff),
+            (b"MM\x00*\x00\x08", Payload::Tiff),
+            (b"BM\x36\x00", Payload::Bmp),
+            (b"\x00\x00\x01\x00\x01\x00", Payload::Ico),
+            (b"\x00\x01\x02\x03", Payload::Unknown),
+        ] {
+            assert_eq!(Payload::sniff(bytes), expected, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn sniffs_ftyp_brands() {
+        assert_eq!(Payload::sniff(&ftyp(&[b"avif", b"mif1"])), Payload::Avif);
+        assert_eq!(Payload::sniff(&ftyp(&[b"mif1", b"avif"])), Payload::Avif);
+        assert_eq!(Payload::sniff(&ftyp(&[b"heic", b"mif1"])), Payload::Heic);
+        assert_eq!(Payload::sniff(&ftyp(&[b"isom", b"mp41"])), Payload::Unknown);
+        // The brands past the box size don't count
+        let mut bytes = ftyp(&[b"mif1"]);
+        bytes.extend(b"avif");
+        assert_eq!(Payload::sniff(&bytes), Payload::Heic);
+    }
+
+    #[test]
+    fn sniffs_text() {
+        for (text, expected) in [
+            ("<!DOCTYPE html><html>", Payload::Html),
+            ("\u{FEFF}  <html>", Payload::Html),
+            ("<svg xmlns=\"http://www.w3.org/2000/svg\">", Payload::Svg),
+            ("<?xml version=\"1.0\"?><svg>", Payload::Svg),
+            (
+                "<?xml version=\"1.0\"?><!DOCTYPE html><html>",
+                Payload::Html,
+            ),
+            ("<?xml version=\"1.0\"?><Error>", Payload::Xml),
+            ("<!-- comment --><svg>", Payload::Svg),
+            (" {\"error\": \"not found\"}", Payload::Json),
+            ("[1, 2]", Payload::Json),
+            ("Not found", Payload::Unknown),
+        ] {
+            assert_eq!(Payload::sniff(text.as_bytes()), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn tells_images_and_decoders() {
+        assert!(Payload::Heic.is_image());
+        assert!(!Payload::Html.is_image());
+        assert_eq!(Payload::Png.image_format(), Some(ImageFormat::Png));
+        assert_eq!(Payload::Avif.image_format(), None);
+        assert_eq!(Payload::Empty.to_string(), "an empty body");
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::worker::{Outcome, ProcessError};
+
+/// Counts the outcomes of the processed items.
+#[derive(Default)]
+pub struct Stats {
+    saved: AtomicUsize,
+    skipped: AtomicUsize,
+    unchanged: AtomicUsize,
+    duplicates: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Stats {
+    pub fn record(&self, result: &Result<Outcome, ProcessError>) {
+        let counter = match result {
+            Ok(Outcome::Saved) => &self.saved,
+            Ok(Outcome::Skipped) => &self.skipped,
+            Ok(Outcome::Unchanged) => &self.unchanged,
+            Ok(Outcome::Duplicate) => &self.duplicates,
+            Err(_) => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of images found unchanged by a refresh.
+    pub fn unchanged(&self) -> usize {
+        self.unchanged.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "saved {}, unchanged {}, duplicates {}, skipped {}, failed {}",
+            self.saved.load(Ordering::Relaxed),
+            self.unchanged.load(Ordering::Relaxed),
+            self.duplicates.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed)
+        )
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fmt, str::FromStr};
+
+/// A number of bytes, parsed from values like `512`, `64K`, `32M` or `1G`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier = match s[digits.len()..].to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            other => return Err(format!("Invalid size unit {:?}", other)),
+        };
+        let value = digits
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid size {:?}: {}", s, e))?;
+        value
+            .checked_mul(multiplier)
+            .map(ByteSize)
+            .ok_or_else(|| format!("Size {:?} is too large", s))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "0"),
+            n if n % (1 << 30) == 0 => write!(f, "{}G", n >> 30),
+            n if n % (1 << 20) == 0 => write!(f, "{}M", n >> 20),
+            n if n % (1 << 10) == 0 => write!(f, "{}K", n >> 10),
+            n => write!(f, "{}", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_suffixes() {
+        assert_eq!("512".parse(), Ok(ByteSize(512)));
+        assert_eq!("512B".parse(), Ok(ByteSize(512)));
+        assert_eq!("64K".parse(), Ok(ByteSize(64 << 10)));
+        assert_eq!("64kb".parse(), Ok(ByteSize(64 << 10)));
+        assert_eq!("32M".parse(), Ok(ByteSize(32 << 20)));
+        assert_eq!("32MiB".parse(), Ok(ByteSize(32 << 20)));
+        assert_eq!(" 1 G ".parse(), Ok(ByteSize(1 << 30)));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!("12X".parse::<ByteSize>().is_err());
+        assert!("K".parse::<ByteSize>().is_err());
+        assert!("-1M".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!("18446744073709551615".parse(), Ok(ByteSize(u64::MAX)));
+        assert!("18446744073709551615K".parse::<ByteSize>().is_err());
+        assert!("17179869184G".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn displays_largest_unit() {
+        assert_eq!(ByteSize(0).to_string(), "0");
+        assert_eq!(ByteSize(1 << 30).to_string(), "1G");
+        assert_eq!(ByteSize(3 << 20).to_string(), "3M");
+        assert_eq!(ByteSize(1536).to_string(), "1536");
+    }
+}
@@ -1,5 +1,14 @@
-use std::{fs, sync::atomic::AtomicBool, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use image::RgbaImage;
 use log::info;
 use thiserror::Error;
 
@@ -8,37 +17,51 @@ use rskachka::{item::Item, item::ParsingError};
 use crate::{
     abort::return_on_flag,
     args::Args,
-    fetcher::{FetchError, Fetcher},
-    images::{save_bytes_as_image, ImagesError},
+    dedup::{Claim, ClaimGuard, InFlight},
+    fetcher::{FetchError, Fetched, Fetcher, Fetchers},
+    group::Group,
+    hosts::host_of,
+    images::{decode_image, save_as_image, ImageOptions, ImagesError},
+    refresh::{ValidatorStore, Validators},
     saving::SavingSemaphore,
+    shared::Shared,
 };
 
 pub struct Worker {
-    fetcher: Fetcher,
-    output_root: String,
-    fields: Vec<i8>,
-    url_field: i8,
-    max_size: u32,
-    extension: String,
-    quality: u8,
+    fetcher: Fetchers,
+    options: ImageOptions,
     resume: bool,
+    validators: Option<ValidatorStore>,
+    in_flight: Arc<InFlight>,
+    deadline: Option<Duration>,
 }
 
-impl From<&Args> for Worker {
-    fn from(args: &Args) -> Self {
+impl Worker {
+    pub fn new(args: &Args, shared: &Shared) -> Self {
+        Worker::with_fetchers(args, shared, Fetchers::new(args, shared))
+    }
+
+    /// A worker getting the images through the given fetchers.
+    pub fn with_fetchers(args: &Args, shared: &Shared, fetcher: Fetchers) -> Self {
         Worker {
-            fetcher: Fetcher::new(Duration::from_secs(args.timeout)),
-            output_root: args.output_root.clone(),
-            fields: args.fields.clone(),
-            url_field: args.url_field,
-            max_size: args.max_size,
-            extension: args.extension.clone(),
-            quality: args.quality,
+            fetcher,
+            options: ImageOptions::from(args),
             resume: args.resume,
+            validators: args.refresh.then(|| ValidatorStore::new(&args.output_root)),
+            in_flight: Arc::clone(&shared.in_flight),
+            deadline: (args.deadline > 0).then(|| Duration::from_secs(args.deadline)),
         }
     }
 }
 
+/// What became of a successfully processed item.
+pub enum Outcome {
+    Saved,
+    Skipped,
+    Unchanged,
+    Duplicate,
+}
+
 #[derive(Error, Debug)]
 pub enum ProcessError {
     #[error("IO error: {0}")]
@@ -50,64 +73,548 @@ pub enum ProcessError {
     #[error("Fetch error: {0}")]
     FetchError(#[from] FetchError),
 
-    #[error("URL parsing error: {0}")]
+    #[error("Image error: {0}")]
     ImagesError(#[from] ImagesError),
 
+    #[error("Disallowed by robots.txt: {0}")]
+    DisallowedByRobots(String),
+
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
+    #[error("Already failed in this run: {0}")]
+    DuplicateOfFailed(String),
+
     #[error("Process error: {0}")]
     Custom(String),
+
+    #[error(transparent)]
+    Shared(Arc<ProcessError>),
+}
+
+impl ProcessError {
+    /// Whether the item may succeed when retried later in the run.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ProcessError::FetchError(err) => {
+                err.is_retryable() || matches!(err, FetchError::HostSuspended(_))
+            }
+            ProcessError::DeadlineExceeded(_) | ProcessError::DuplicateOfFailed(_) => true,
+            ProcessError::Shared(err) => err.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether robots.txt kept us from fetching the image.
+    pub fn is_disallowed(&self) -> bool {
+        match self {
+            ProcessError::DisallowedByRobots(_) => true,
+            ProcessError::Shared(err) => err.is_disallowed(),
+            _ => false,
+        }
+    }
+
+    /// Whether another URL for the same image may succeed.
+    fn is_url_specific(&self) -> bool {
+        matches!(
+            self,
+            ProcessError::FetchError(_)
+                | ProcessError::DisallowedByRobots(_)
+                | ProcessError::ImagesError(
+                    ImagesError::Image(_)
+                        | ImagesError::NotAnImage(_)
+                        | ImagesError::Unsupported(_)
+                )
+        )
+    }
+}
+
+/// Identifies what gets saved for the item, so each image is processed once.
+/// The fallbacks and the original are part of it, as the items sharing
+/// the outcome of an image have to be able to get the same one.
+fn claim_key(item: &Item) -> String {
+    // URLs have no whitespace once parsed
+    let mut key = item.urls().collect::<Vec<_>>().join(" ");
+    if let Some(crop) = &item.crop {
+        key += &format!("#{}", crop);
+    }
+    if let Some(original) = &item.original {
+        key += &format!(" {}", original.to_string_lossy());
+    }
+    key
+}
+
+fn reuse(item: &Item, path: Option<PathBuf>) -> Result<Outcome, ProcessError> {
+    let path = path.ok_or_else(|| ProcessError::DuplicateOfFailed(item.url.clone()))?;
+    if path != item.path && !item.path.exists() {
+        fs::copy(&path, &item.path)?;
+    }
+    info!("Duplicate {}", item.url);
+    Ok(Outcome::Duplicate)
+}
+
+fn create_parent(path: &Path) -> Result<(), ProcessError> {
+    fs::create_dir_all(path.parent().ok_or_else(|| {
+        ProcessError::Custom(format!("Can't infer parent for {}", path.to_str().unwrap()))
+    })?)
+    .map_err(ProcessError::IO)
+}
+
+/// Receives the outcome of every processed item.
+pub type Report<'a> = &'a (dyn Fn(Item, Result<Outcome, ProcessError>) + Sync);
+
+/// The items of a group claimed for processing, passed from the download
+/// stage to the image stage and back when falling back to the next URL.
+pub struct Job {
+    /// The items done with before fetching, e.g. skipped
+    results: Vec<(Item, Result<Outcome, ProcessError>)>,
+    claimed: Vec<(Item, ClaimGuard)>,
+    /// The position of the URL to fetch next
+    position: usize,
+    /// When fetching and saving the images of the job has to be over
+    deadline: Option<Instant>,
+}
+
+impl Job {
+    /// When fetching and saving the images of the job has to be over.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The URL to fetch next, unless no item needs the image.
+    pub fn url(&self) -> Option<&str> {
+        let (item, _) = self.claimed.first()?;
+        item.urls().nth(self.position)
+    }
+
+    /// Moves on to the next URL if another may succeed where this one failed.
+    fn fall_back(&mut self, url: &str, err: &ProcessError) -> bool {
+        let (item, _) = &self.claimed[0];
+        if !err.is_url_specific() || item.urls().nth(self.position + 1).is_none() {
+            return false;
+        }
+        info!("Falling back from {}: {}", url, err);
+        self.position += 1;
+        true
+    }
+}
+
+/// What the download stage got for a job.
+pub enum Download {
+    /// The body fetched from the URL
+    Fetched(Vec<u8>, Validators, String),
+    Unchanged(String),
+    Failed(ProcessError),
+    Stopped,
+    /// No item needs the image
+    Nothing,
+    /// The job falls back to a URL of another host, to fetch under a permit for it
+    Elsewhere,
+}
+
+/// What the image stage made of a download.
+pub enum Finished {
+    /// The outcome of every item, including the duplicates attached meanwhile
+    Done(Vec<(Item, Result<Outcome, ProcessError>)>),
+    /// The body wasn't a usable image, so the job goes back to fetch the next URL
+    Refetch(Job),
 }
 
 impl Worker {
-    pub fn process(
+    /// Claims the images of the group, never waiting for other records.
+    /// The duplicates of images being processed elsewhere get attached to them.
+    pub fn claim(&self, group: Group) -> Job {
+        let mut job = Job {
+            results: Vec::new(),
+            claimed: Vec::new(),
+            position: 0,
+            // One deadline for all the URLs and the saves of the items
+            deadline: self.deadline.map(|deadline| Instant::now() + deadline),
+        };
+        for item in group.items {
+            // Skip the items we are resuming and the files exist for
+            if item.path.exists() && self.resume {
+                info!("Skipping {}", item.url);
+                job.results.push((item, Ok(Outcome::Skipped)));
+                continue;
+            }
+            if let Err(err) = create_parent(&item.path) {
+                job.results.push((item, Err(err)));
+                continue;
+            }
+
+            // Process every image once, reusing the result for the duplicates
+            match self.in_flight.claim(&claim_key(&item), item) {
+                Claim::First(guard, item) => job.claimed.push((item, guard)),
+                Claim::Done(item, path) => {
+                    let result = reuse(&item, path);
+                    job.results.push((item, result));
+                }
+                Claim::Attached => {}
+            }
+        }
+        job
+    }
+
+    /// The validators to make the request conditional with, if refreshing.
+    pub fn known_validators(&self, job: &Job, url: &str) -> Validators {
+        // Only ask for changes when we still have every image
+        match &self.validators {
+            Some(store) if job.claimed.iter().all(|(item, _)| item.path.exists()) => {
+                store.load(&job.claimed[0].0.path, url)
+            }
+            _ => Validators::default(),
+        }
+    }
+
+    /// Fetches the image of the job, trying the URLs of the host in order until one succeeds.
+    pub fn download(&self, job: &mut Job, stopped: &AtomicBool) -> Download {
+        loop {
+            let Some(url) = job.url().map(str::to_string) else {
+                return Download::Nothing;
+            };
+            if stopped.load(Ordering::Relaxed) {
+                info!("Shutting down...");
+                return Download::Stopped;
+            }
+            let known = self.known_validators(job, &url);
+            let fetched = self
+                .fetcher
+                .fetch_if_changed(&url, &known, job.deadline(), stopped);
+            if let Some(download) = self.downloaded(job, url, fetched) {
+                return download;
+            }
+        }
+    }
+
+    /// Turns the result of fetching the URL into a download,
+    /// or `None` if the job falls back to the next URL of the same host.
+    pub fn downloaded(
+        &self,
+        job: &mut Job,
+        url: String,
+        fetched: Result<Fetched, FetchError>,
+    ) -> Option<Download> {
+        match fetched {
+            Ok(Fetched::Modified(bytes, validators)) => {
+                if job.position > 0 {
+                    let item = &job.claimed[0].0;
+                    info!("Used fallback #{} for {}: {}", job.position, item.url, url);
+                }
+                Some(Download::Fetched(bytes, validators, url))
+            }
+            Ok(Fetched::Unchanged) => Some(Download::Unchanged(url)),
+            Err(e) => {
+                let err = match e {
+                    FetchError::DisallowedByRobots => ProcessError::DisallowedByRobots(url.clone()),
+                    FetchError::Deadline => ProcessError::DeadlineExceeded(url.clone()),
+                    e => ProcessError::FetchError(e),
+                };
+                if !job.fall_back(&url, &err) {
+                    return Some(Download::Failed(err));
+                }
+                // The host permit only covers the host of the previous URL
+                let moved = job.url().is_some_and(|next| host_of(next) != host_of(&url));
+                moved.then_some(Download::Elsewhere)
+            }
+        }
+    }
+
+    /// Decodes the downloaded image and saves it for every claimed item,
+    /// then finishes the claims along with the duplicates attached to them.
+    pub fn finish(
         &self,
-        record: &csv::StringRecord,
+        mut job: Job,
+        download: Download,
         stopped: &AtomicBool,
         saving: &SavingSemaphore,
-    ) -> Result<(), ProcessError> {
-        // Parse the record into an item
-        let item = Item::from_record(
-            record,
-            &self.fields,
-            self.url_field,
-            &self.output_root,
-            &self.extension,
-        )
-        .map_err(|e| ProcessError::Custom(format!("Error parsing record: {}", e)))?;
+    ) -> Finished {
+        let count = job.claimed.len();
+        let outcomes = match download {
+            Download::Fetched(..) if stopped.load(Ordering::Relaxed) => {
+                info!("Shutting down...");
+                (0..count).map(|_| Ok(Outcome::Skipped)).collect()
+            }
+            Download::Fetched(bytes, validators, url) => {
+                let image = match decode_image(&bytes).map_err(|e| images_error(e, &url)) {
+                    Ok(image) => image,
+                    Err(err) if job.fall_back(&url, &err) => return Finished::Refetch(job),
+                    Err(err) => return self.finish_claims(job, failed(err, count)),
+                };
+                drop(bytes);
+                match self.save_original(&job, &image, &url, stopped, saving) {
+                    Ok(()) => job
+                        .claimed
+                        .iter()
+                        .map(|(item, _)| {
+                            self.save_item(
+                                item,
+                                &image,
+                                &url,
+                                &validators,
+                                job.deadline,
+                                stopped,
+                                saving,
+                            )
+                        })
+                        .collect(),
+                    Err(err) => failed(err, count),
+                }
+            }
+            Download::Unchanged(url) => {
+                info!("Unchanged {}", url);
+                (0..count).map(|_| Ok(Outcome::Unchanged)).collect()
+            }
+            Download::Failed(err) => failed(err, count),
+            Download::Stopped | Download::Nothing | Download::Elsewhere => {
+                (0..count).map(|_| Ok(Outcome::Skipped)).collect()
+            }
+        };
+        self.finish_claims(job, outcomes)
+    }
 
-        // Finish if we are resuming and the file exists
-        if item.path.exists() && self.resume {
-            info!("Skipping {}", item.url);
-            return Ok(());
+    fn finish_claims(
+        &self,
+        job: Job,
+        outcomes: impl IntoIterator<Item = Result<Outcome, ProcessError>>,
+    ) -> Finished {
+        let mut results = job.results;
+        for ((item, guard), outcome) in job.claimed.into_iter().zip(outcomes) {
+            let path = (outcome.is_ok() && item.path.exists()).then(|| item.path.clone());
+            for duplicate in guard.finish(path.clone()) {
+                let result = reuse(&duplicate, path.clone());
+                results.push((duplicate, result));
+            }
+            results.push((item, outcome));
         }
+        Finished::Done(results)
+    }
 
-        // Create all subdirectories
-        fs::create_dir_all(item.path.parent().ok_or_else(|| {
-            ProcessError::Custom(format!(
-                "Can't infer parent for {}",
-                item.path.to_str().unwrap()
-            ))
-        })?)
-        .map_err(ProcessError::IO)?;
-
-        // Fetch the record as bytes
-        return_on_flag!(stopped, || info!("Shutting down..."));
-        let bytes = self
-            .fetcher
-            .fetch(&item.url)
-            .map_err(ProcessError::FetchError)?;
+    /// Saves the image as a whole if asked to, unless done in this run already.
+    fn save_original(
+        &self,
+        job: &Job,
+        image: &RgbaImage,
+        url: &str,
+        stopped: &AtomicBool,
+        saving: &SavingSemaphore,
+    ) -> Result<(), ProcessError> {
+        let Some(original) = job.claimed[0].0.original.as_deref() else {
+            return Ok(());
+        };
+        if let Some(guard) = self.in_flight.claim_once(&original.to_string_lossy()) {
+            create_parent(original)?;
+            save_as_image(
+                image,
+                None,
+                original,
+                &self.options,
+                job.deadline,
+                stopped,
+                saving,
+            )
+            .map_err(|e| images_error(e, url))?;
+            guard.finish(Some(original.to_path_buf()));
+        }
+        Ok(())
+    }
 
+    #[allow(clippy::too_many_arguments)]
+    fn save_item(
+        &self,
+        item: &Item,
+        image: &RgbaImage,
+        url: &str,
+        validators: &Validators,
+        deadline: Option<Instant>,
+        stopped: &AtomicBool,
+        saving: &SavingSemaphore,
+    ) -> Result<Outcome, ProcessError> {
         // Process the image and save
-        return_on_flag!(stopped, || info!("Shutting down..."));
-        save_bytes_as_image(
-            &bytes,
+        save_as_image(
+            image,
+            item.crop.as_ref(),
             &item.path,
-            self.max_size,
-            &self.extension,
-            self.quality,
+            &self.options,
+            deadline,
             stopped,
             saving,
         )
-        .map_err(ProcessError::ImagesError)
-        .map(|_| info!("Saved {}", item.url))
+        .map_err(|e| images_error(e, url))?;
+
+        // Remember what we saved for the next refresh
+        return_on_flag!(stopped, || info!("Shutting down..."), Outcome::Skipped);
+        if let Some(store) = &self.validators {
+            store.store(&item.path, url, validators)?;
+        }
+        info!("Saved {}", url);
+        Ok(Outcome::Saved)
+    }
+}
+
+/// Fails all the claimed items with the error, shared if there are several.
+fn failed(err: ProcessError, count: usize) -> Vec<Result<Outcome, ProcessError>> {
+    if count == 1 {
+        return vec![Err(err)];
+    }
+    let err = Arc::new(err);
+    (0..count)
+        .map(|_| Err(ProcessError::Shared(Arc::clone(&err))))
+        .collect()
+}
+
+fn images_error(e: ImagesError, url: &str) -> ProcessError {
+    match e {
+        ImagesError::Deadline => ProcessError::DeadlineExceeded(url.to_string()),
+        e => ProcessError::ImagesError(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Cursor};
+
+    use clap::{CommandFactory, FromArgMatches};
+    use image::{ImageOutputFormat, Rgba};
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Serves the bodies from memory, counting the requests.
+    #[derive(Default)]
+    struct MemoryFetcher {
+        bodies: HashMap<String, Vec<u8>>,
+        requests: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl Fetcher for MemoryFetcher {
+        fn fetch(
+            &self,
+            url: &str,
+            _deadline: Option<Instant>,
+            _stopped: &AtomicBool,
+        ) -> Result<Vec<u8>, FetchError> {
+            self.requests.lock().unwrap().push(url.to_string());
+            self.bodies
+                .get(url)
+                .cloned()
+                .ok_or_else(|| FetchError::InvalidUrl(url.to_string()))
+        }
+    }
+
+    fn png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn output_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rskachka-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        root
+    }
+
+    fn item(root: &Path, urls: &[&str]) -> Item {
+        Item {
+            id: "1".to_string(),
+            url: urls[0].to_string(),
+            fallbacks: urls[1..].iter().map(|url| url.to_string()).collect(),
+            position: None,
+            path: root.join("a").join("1.webp"),
+            crop: None,
+            original: None,
+        }
+    }
+
+    fn worker(root: &Path, fetcher: Arc<MemoryFetcher>) -> Worker {
+        // The short -q of --quality clashes with --quiet, which debug builds assert on
+        let command = Args::command().mut_arg("quality", |arg| arg.short(None));
+        let root = root.to_string_lossy();
+        let matches = command.get_matches_from(["rskachka", "-s", "source.csv", "-o", &root]);
+        let args = Args::from_arg_matches(&matches).unwrap();
+        let shared = Shared::new(&args, &Config::default()).unwrap();
+        let mut fetchers = Fetchers::new(&args, &shared);
+        fetchers.register("mem", fetcher);
+        Worker::with_fetchers(&args, &shared, fetchers)
+    }
+
+    /// Runs the group through both stages, refetching while the image is unusable.
+    fn process(worker: &Worker, mut job: Job) -> Vec<(Item, Result<Outcome, ProcessError>)> {
+        let (stopped, saving) = (AtomicBool::new(false), SavingSemaphore::new());
+        loop {
+            let download = worker.download(&mut job, &stopped);
+            if let Download::Elsewhere = download {
+                continue;
+            }
+            match worker.finish(job, download, &stopped, &saving) {
+                Finished::Done(results) => return results,
+                Finished::Refetch(refetch) => job = refetch,
+            }
+        }
+    }
+
+    #[test]
+    fn saves_image_from_registered_fetcher() {
+        let root = output_root("fetchers");
+        let fetcher = Arc::new(MemoryFetcher {
+            bodies: HashMap::from([("mem://a".to_string(), png())]),
+            ..Default::default()
+        });
+        let worker = worker(&root, Arc::clone(&fetcher));
+
+        let job = worker.claim(Group::new(item(&root, &["mem://a"])));
+        let results = process(&worker, job);
+        assert!(matches!(results[..], [(_, Ok(Outcome::Saved))]));
+        assert!(root.join("a").join("1.webp").exists());
+        assert_eq!(*fetcher.requests.lock().unwrap(), ["mem://a"]);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn falls_back_from_unusable_image() {
+        let root = output_root("fallback");
+        let fetcher = Arc::new(MemoryFetcher {
+            bodies: HashMap::from([
+                ("mem://broken".to_string(), b"<html>".to_vec()),
+                ("mem://b".to_string(), png()),
+            ]),
+            ..Default::default()
+        });
+        let worker = worker(&root, Arc::clone(&fetcher));
+
+        let job = worker.claim(Group::new(item(
+            &root,
+            &["mem://missing", "mem://broken", "mem://b"],
+        )));
+        let results = process(&worker, job);
+        assert!(matches!(results[..], [(_, Ok(Outcome::Saved))]));
+        assert_eq!(
+            *fetcher.requests.lock().unwrap(),
+            ["mem://missing", "mem://broken", "mem://b"]
+        );
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn duplicates_with_other_fallbacks_are_claimed_separately() {
+        let root = output_root("duplicates");
+        let fetcher = Arc::new(MemoryFetcher {
+            bodies: HashMap::from([("mem://c".to_string(), png())]),
+            ..Default::default()
+        });
+        let worker = worker(&root, Arc::clone(&fetcher));
+
+        let first = worker.claim(Group::new(item(&root, &["mem://missing", "mem://b"])));
+        let mut other = item(&root, &["mem://missing", "mem://c"]);
+        other.path = root.join("b").join("2.webp");
+        let second = worker.claim(Group::new(other));
+        assert!(matches!(process(&worker, first)[..], [(_, Err(_))]));
+        assert!(matches!(
+            process(&worker, second)[..],
+            [(_, Ok(Outcome::Saved))]
+        ));
+        fs::remove_dir_all(&root).ok();
     }
 }
@@ -9,7 +9,7 @@ use std::{
 
 pub fn count_lines(buffer: &[u8]) -> usize {
     let num_threads = num_cpus::get();
-    let buffer_size = (buffer.len() + num_threads - 1) / num_threads;
+    let buffer_size = buffer.len().div_ceil(num_threads);
 
     let count = Arc::new(AtomicUsize::new(0));
 